@@ -0,0 +1,785 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::disassembler::disassemble;
+use crate::execute::{slice_bounds, ErrorKind, Interpreter, VMError, VMResult};
+use crate::gc::value_size;
+use crate::object::Object;
+use crate::value::Value;
+
+type Method = (
+    &'static str,
+    fn(&mut Interpreter, Option<Value>, Vec<Value>) -> VMResult,
+);
+
+// A prototype with the given native methods.
+fn prototype(methods: &[Method]) -> Object {
+    let mut prototype = Object::new();
+    for &(name, method) in methods {
+        prototype.set(name.to_string(), Value::NativeFunction(method));
+    }
+    prototype
+}
+
+// The array an array method was called on.
+fn this_array(base: &Option<Value>, method: &str) -> Result<Rc<RefCell<Vec<Value>>>, VMError> {
+    match base {
+        Some(Value::Array(ref array)) => Ok(array.clone()),
+        _ => Err(VMError::Message(
+            ErrorKind::Type,
+            format!("{} must be called on an array", method),
+        )),
+    }
+}
+
+fn callback_argument(args: &[Value], index: usize, method: &str) -> Result<Value, VMError> {
+    match args.get(index) {
+        Some(
+            callback @ (Value::NativeFunction(_)
+            | Value::Function(..)
+            | Value::Closure(_)
+            | Value::BoundMethod(_)
+            | Value::Class(_)),
+        ) => Ok(callback.clone()),
+        _ => Err(VMError::Message(
+            ErrorKind::Type,
+            format!("{} expects a function", method),
+        )),
+    }
+}
+
+// Calls `callback(element, index, array)` for each element until it returns
+// Some. Callbacks see the elements the array had when the method was called,
+// even if they change it.
+fn each_element<T>(
+    interpreter: &mut Interpreter,
+    array: &Rc<RefCell<Vec<Value>>>,
+    callback: &Value,
+    mut f: impl FnMut(Value, Value) -> Option<T>,
+) -> Result<Option<T>, VMError> {
+    let elements = array.borrow().clone();
+    for (index, element) in elements.into_iter().enumerate() {
+        let args = vec![
+            element.clone(),
+            Value::Number(index as i32),
+            Value::Array(array.clone()),
+        ];
+        let result = interpreter.call_value(callback.clone(), None, args)?;
+        if let Some(found) = f(element, result) {
+            return Ok(Some(found));
+        }
+    }
+    Ok(None)
+}
+
+fn new_array(interpreter: &mut Interpreter, values: Vec<Value>) -> VMResult {
+    Ok(Value::Array(interpreter.heap.array(values)?))
+}
+
+fn array_push(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "push")?;
+    interpreter
+        .heap
+        .reserve(args.iter().map(value_size).sum())?;
+    array.borrow_mut().extend(args);
+    Ok(Value::Nothing)
+}
+
+// Removes the last element and returns it, or nil if the array is empty.
+fn array_pop(_interpreter: &mut Interpreter, base: Option<Value>, _args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "pop")?;
+    let last = array.borrow_mut().pop();
+    Ok(last.unwrap_or(Value::Nothing))
+}
+
+// Removes the first element and returns it, or nil if the array is empty.
+fn array_shift(_interpreter: &mut Interpreter, base: Option<Value>, _args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "shift")?;
+    let mut array = array.borrow_mut();
+    if array.is_empty() {
+        Ok(Value::Nothing)
+    } else {
+        Ok(array.remove(0))
+    }
+}
+
+// Adds the arguments to the front of the array, and returns its new length.
+fn array_unshift(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "unshift")?;
+    interpreter
+        .heap
+        .reserve(args.iter().map(value_size).sum())?;
+    let mut array = array.borrow_mut();
+    array.splice(0..0, args);
+    Ok(Value::Number(array.len() as i32))
+}
+
+// `a.slice(start, end)` is a new array of the elements from start up to but
+// not including end, or to the end of the array if end is left out. The
+// bounds work as they do in `a[start:end]`.
+fn array_slice(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "slice")?;
+    let length = array.borrow().len();
+    let mut args = args.into_iter();
+    let start = args.next().unwrap_or(Value::Nothing);
+    let end = args.next().unwrap_or(Value::Nothing);
+    let (start, end) = slice_bounds(start, end, length)?;
+
+    let slice = array.borrow()[start..end].to_vec();
+    new_array(interpreter, slice)
+}
+
+// `a.splice(start, count, values...)` removes count elements from start, or
+// all of them if count is left out, and puts the values in their place. It
+// returns the removed elements.
+fn array_splice(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "splice")?;
+    let length = array.borrow().len();
+    let start = match position_argument(&args, 0, length, "splice")? {
+        Some(start) => start,
+        None => {
+            return Err(VMError::Message(
+                ErrorKind::Type,
+                "splice expects a start position".to_string(),
+            ))
+        }
+    };
+    let count = match args.get(1) {
+        None => length - start,
+        Some(Value::Number(count)) if *count >= 0 => (*count as usize).min(length - start),
+        Some(_) => {
+            return Err(VMError::Message(
+                ErrorKind::Type,
+                "splice expects a count of 0 or more".to_string(),
+            ))
+        }
+    };
+
+    let values = args.into_iter().skip(2).collect::<Vec<_>>();
+    interpreter
+        .heap
+        .reserve(values.iter().map(value_size).sum())?;
+    let removed = array
+        .borrow_mut()
+        .splice(start..start + count, values)
+        .collect();
+    new_array(interpreter, removed)
+}
+
+// A new array of the elements followed by the arguments, with the elements
+// of array arguments rather than the arrays themselves.
+fn array_concat(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "concat")?;
+    let mut values = array.borrow().clone();
+    for arg in args {
+        match arg {
+            Value::Array(ref other) => values.extend(other.borrow().iter().cloned()),
+            arg => values.push(arg),
+        }
+    }
+    new_array(interpreter, values)
+}
+
+// The index of the first element that's the same as the argument, or -1.
+fn array_index_of(
+    _interpreter: &mut Interpreter,
+    base: Option<Value>,
+    args: Vec<Value>,
+) -> VMResult {
+    let array = this_array(&base, "indexOf")?;
+    let needle = args.first().cloned().unwrap_or(Value::Nothing);
+    let index = array.borrow().iter().position(|value| value.same(&needle));
+    Ok(Value::Number(index.map_or(-1, |index| index as i32)))
+}
+
+fn array_includes(
+    _interpreter: &mut Interpreter,
+    base: Option<Value>,
+    args: Vec<Value>,
+) -> VMResult {
+    let array = this_array(&base, "includes")?;
+    let needle = args.first().cloned().unwrap_or(Value::Nothing);
+    let found = array.borrow().iter().any(|value| value.same(&needle));
+    Ok(Value::Boolean(found))
+}
+
+// Reverses the array in place and returns it.
+fn array_reverse(
+    _interpreter: &mut Interpreter,
+    base: Option<Value>,
+    _args: Vec<Value>,
+) -> VMResult {
+    let array = this_array(&base, "reverse")?;
+    array.borrow_mut().reverse();
+    Ok(Value::Array(array))
+}
+
+// The elements as print shows them, separated by the argument or by ",".
+fn array_join(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "join")?;
+    let separator = match args.first() {
+        None => ",",
+        Some(_) => string_argument(&args, 0, "join")?,
+    };
+    let strings: Vec<String> = array.borrow().iter().map(Value::printed).collect();
+    new_string(interpreter, strings.join(separator))
+}
+
+fn array_map(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "map")?;
+    let callback = callback_argument(&args, 0, "map")?;
+    let mut values = Vec::new();
+    each_element::<()>(interpreter, &array, &callback, |_, result| {
+        values.push(result);
+        None
+    })?;
+    new_array(interpreter, values)
+}
+
+fn array_filter(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "filter")?;
+    let callback = callback_argument(&args, 0, "filter")?;
+    let mut values = Vec::new();
+    each_element::<()>(interpreter, &array, &callback, |element, result| {
+        if result.is_truthy() {
+            values.push(element);
+        }
+        None
+    })?;
+    new_array(interpreter, values)
+}
+
+// `a.reduce(f, initial)` folds the elements with
+// `f(accumulator, element, index, array)`, starting from initial, or from
+// the first element if initial is left out.
+fn array_reduce(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "reduce")?;
+    let callback = callback_argument(&args, 0, "reduce")?;
+    let elements = array.borrow().clone();
+    let mut elements = elements.into_iter().enumerate();
+    let mut accumulator = match args.get(1) {
+        Some(initial) => initial.clone(),
+        None => match elements.next() {
+            Some((_, first)) => first,
+            None => {
+                return Err(VMError::Message(
+                    ErrorKind::Type,
+                    "reduce of an empty array expects an initial value".to_string(),
+                ))
+            }
+        },
+    };
+
+    for (index, element) in elements {
+        let args = vec![
+            accumulator,
+            element,
+            Value::Number(index as i32),
+            Value::Array(array.clone()),
+        ];
+        accumulator = interpreter.call_value(callback.clone(), None, args)?;
+    }
+    Ok(accumulator)
+}
+
+fn array_for_each(
+    interpreter: &mut Interpreter,
+    base: Option<Value>,
+    args: Vec<Value>,
+) -> VMResult {
+    let array = this_array(&base, "forEach")?;
+    let callback = callback_argument(&args, 0, "forEach")?;
+    each_element::<()>(interpreter, &array, &callback, |_, _| None)?;
+    Ok(Value::Nothing)
+}
+
+// The first element the callback is truthy for, or nil.
+fn array_find(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "find")?;
+    let callback = callback_argument(&args, 0, "find")?;
+    let found = each_element(interpreter, &array, &callback, |element, result| {
+        if result.is_truthy() {
+            Some(element)
+        } else {
+            None
+        }
+    })?;
+    Ok(found.unwrap_or(Value::Nothing))
+}
+
+fn array_some(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "some")?;
+    let callback = callback_argument(&args, 0, "some")?;
+    let found = each_element(interpreter, &array, &callback, |_, result| {
+        if result.is_truthy() {
+            Some(())
+        } else {
+            None
+        }
+    })?;
+    Ok(Value::Boolean(found.is_some()))
+}
+
+fn array_every(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "every")?;
+    let callback = callback_argument(&args, 0, "every")?;
+    let failed = each_element(interpreter, &array, &callback, |_, result| {
+        if result.is_truthy() {
+            None
+        } else {
+            Some(())
+        }
+    })?;
+    Ok(Value::Boolean(failed.is_none()))
+}
+
+// Without a comparator, numbers sort in increasing order and strings by
+// their characters.
+fn default_order(a: &Value, b: &Value) -> Result<Ordering, VMError> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => Ok(a.cmp(b)),
+        (Value::String(a), Value::String(b)) => Ok(a.cmp(b)),
+        _ => Err(VMError::Message(
+            ErrorKind::Type,
+            "sort without a comparator expects only numbers or only strings".to_string(),
+        )),
+    }
+}
+
+// A stable merge sort. Unlike the slice sorts it stops at the first error,
+// and it doesn't mind comparators that contradict themselves.
+fn merge_sort(
+    values: Vec<Value>,
+    compare: &mut dyn FnMut(&Value, &Value) -> Result<Ordering, VMError>,
+) -> Result<Vec<Value>, VMError> {
+    if values.len() <= 1 {
+        return Ok(values);
+    }
+
+    let mut left = values;
+    let right = left.split_off(left.len() / 2);
+    let left = merge_sort(left, compare)?;
+    let right = merge_sort(right, compare)?;
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        if compare(a, b)? == Ordering::Greater {
+            merged.extend(right.next());
+        } else {
+            merged.extend(left.next());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+// `a.sort(compare)` sorts the array in place and returns it. The comparator
+// returns a negative number if its first argument goes first, a positive
+// one if the second does, and 0 to keep their order.
+fn array_sort(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let array = this_array(&base, "sort")?;
+    let comparator = match args.first() {
+        None => None,
+        Some(_) => Some(callback_argument(&args, 0, "sort")?),
+    };
+
+    let elements = array.borrow().clone();
+    let sorted = merge_sort(elements, &mut |a, b| match comparator {
+        None => default_order(a, b),
+        Some(ref comparator) => {
+            let args = vec![a.clone(), b.clone()];
+            match interpreter.call_value(comparator.clone(), None, args)? {
+                Value::Number(n) => Ok(n.cmp(&0)),
+                _ => Err(VMError::Message(
+                    ErrorKind::Type,
+                    "sort comparator must return a number".to_string(),
+                )),
+            }
+        }
+    })?;
+    *array.borrow_mut() = sorted;
+    Ok(Value::Array(array))
+}
+
+// The string a string method was called on.
+fn this_string<'a>(base: &'a Option<Value>, method: &str) -> Result<&'a str, VMError> {
+    match base {
+        Some(Value::String(ref string)) => Ok(string),
+        _ => Err(VMError::Message(
+            ErrorKind::Type,
+            format!("{} must be called on a string", method),
+        )),
+    }
+}
+
+fn string_argument<'a>(args: &'a [Value], index: usize, method: &str) -> Result<&'a str, VMError> {
+    match args.get(index) {
+        Some(Value::String(ref string)) => Ok(string),
+        _ => Err(VMError::Message(
+            ErrorKind::Type,
+            format!("{} expects a string argument", method),
+        )),
+    }
+}
+
+// A position from 0 to `length` in a string or an array, or None if the
+// argument is left out. String positions count characters, not bytes.
+fn position_argument(
+    args: &[Value],
+    index: usize,
+    length: usize,
+    method: &str,
+) -> Result<Option<usize>, VMError> {
+    match args.get(index) {
+        None => Ok(None),
+        Some(Value::Number(n)) if *n >= 0 && *n as usize <= length => Ok(Some(*n as usize)),
+        Some(Value::Number(_)) => Err(VMError::Message(
+            ErrorKind::Range,
+            format!("{} position out of range", method),
+        )),
+        Some(_) => Err(VMError::Message(
+            ErrorKind::Type,
+            format!("{} expects a number", method),
+        )),
+    }
+}
+
+// Where the character at `position` starts in the UTF-8 of the string.
+fn byte_offset(string: &str, position: usize) -> usize {
+    string
+        .char_indices()
+        .nth(position)
+        .map_or(string.len(), |(offset, _)| offset)
+}
+
+fn chars(string: &str) -> impl Iterator<Item = &str> {
+    string
+        .char_indices()
+        .map(move |(offset, c)| &string[offset..offset + c.len_utf8()])
+}
+
+fn new_string(interpreter: &mut Interpreter, string: String) -> VMResult {
+    let string = Value::String(string);
+    interpreter.heap.reserve(value_size(&string))?;
+    Ok(string)
+}
+
+fn new_strings<'a>(
+    interpreter: &mut Interpreter,
+    strings: impl Iterator<Item = &'a str>,
+) -> VMResult {
+    let strings = strings.map(|s| Value::String(s.to_string())).collect();
+    Ok(Value::Array(interpreter.heap.array(strings)?))
+}
+
+// `s.substring(start, end)` is the characters from start up to but not
+// including end, or to the end of the string if end is left out.
+fn string_substring(
+    interpreter: &mut Interpreter,
+    base: Option<Value>,
+    args: Vec<Value>,
+) -> VMResult {
+    let string = this_string(&base, "substring")?;
+    let length = string.chars().count();
+    let start = match position_argument(&args, 0, length, "substring")? {
+        Some(start) => start,
+        None => {
+            return Err(VMError::Message(
+                ErrorKind::Type,
+                "substring expects a start position".to_string(),
+            ))
+        }
+    };
+    let end = position_argument(&args, 1, length, "substring")?.unwrap_or(length);
+    if start > end {
+        return Err(VMError::Message(
+            ErrorKind::Range,
+            "substring start is after its end".to_string(),
+        ));
+    }
+
+    let substring = string[byte_offset(string, start)..byte_offset(string, end)].to_string();
+    new_string(interpreter, substring)
+}
+
+// The character position of the first occurrence of the argument, or -1.
+fn string_index_of(
+    _interpreter: &mut Interpreter,
+    base: Option<Value>,
+    args: Vec<Value>,
+) -> VMResult {
+    let string = this_string(&base, "indexOf")?;
+    let needle = string_argument(&args, 0, "indexOf")?;
+    Ok(Value::Number(match string.find(needle) {
+        Some(offset) => string[..offset].chars().count() as i32,
+        None => -1,
+    }))
+}
+
+// Splitting on "" gives the characters of the string.
+fn string_split(interpreter: &mut Interpreter, base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let string = this_string(&base, "split")?;
+    let separator = string_argument(&args, 0, "split")?;
+    if separator.is_empty() {
+        new_strings(interpreter, chars(string))
+    } else {
+        new_strings(interpreter, string.split(separator))
+    }
+}
+
+fn string_trim(interpreter: &mut Interpreter, base: Option<Value>, _args: Vec<Value>) -> VMResult {
+    let string = this_string(&base, "trim")?;
+    new_string(interpreter, string.trim().to_string())
+}
+
+fn string_to_upper_case(
+    interpreter: &mut Interpreter,
+    base: Option<Value>,
+    _args: Vec<Value>,
+) -> VMResult {
+    let string = this_string(&base, "toUpperCase")?;
+    new_string(interpreter, string.to_uppercase())
+}
+
+fn string_to_lower_case(
+    interpreter: &mut Interpreter,
+    base: Option<Value>,
+    _args: Vec<Value>,
+) -> VMResult {
+    let string = this_string(&base, "toLowerCase")?;
+    new_string(interpreter, string.to_lowercase())
+}
+
+// Replaces the first occurrence of the pattern, like JavaScript does for
+// string patterns.
+fn string_replace(
+    interpreter: &mut Interpreter,
+    base: Option<Value>,
+    args: Vec<Value>,
+) -> VMResult {
+    let string = this_string(&base, "replace")?;
+    let pattern = string_argument(&args, 0, "replace")?;
+    let replacement = string_argument(&args, 1, "replace")?;
+    new_string(interpreter, string.replacen(pattern, replacement, 1))
+}
+
+fn string_starts_with(
+    _interpreter: &mut Interpreter,
+    base: Option<Value>,
+    args: Vec<Value>,
+) -> VMResult {
+    let string = this_string(&base, "startsWith")?;
+    let prefix = string_argument(&args, 0, "startsWith")?;
+    Ok(Value::Boolean(string.starts_with(prefix)))
+}
+
+fn string_ends_with(
+    _interpreter: &mut Interpreter,
+    base: Option<Value>,
+    args: Vec<Value>,
+) -> VMResult {
+    let string = this_string(&base, "endsWith")?;
+    let suffix = string_argument(&args, 0, "endsWith")?;
+    Ok(Value::Boolean(string.ends_with(suffix)))
+}
+
+// The characters of the string, each a string of its own.
+fn string_chars(interpreter: &mut Interpreter, base: Option<Value>, _args: Vec<Value>) -> VMResult {
+    let string = this_string(&base, "chars")?;
+    new_strings(interpreter, chars(string))
+}
+
+fn object_create(
+    interpreter: &mut Interpreter,
+    _base: Option<Value>,
+    args: Vec<Value>,
+) -> VMResult {
+    let prototype = match args.first() {
+        Some(Value::Object(ref prototype)) => Some(prototype.clone()),
+        Some(Value::Nothing) => None,
+        _ => {
+            return Err(VMError::Message(
+                ErrorKind::Type,
+                "Object.create expects an object or nil".to_string(),
+            ))
+        }
+    };
+
+    let object = Object::with_prototype(prototype);
+    Ok(Value::Object(interpreter.heap.object(object)?))
+}
+
+fn object_get_prototype_of(
+    interpreter: &mut Interpreter,
+    _base: Option<Value>,
+    args: Vec<Value>,
+) -> VMResult {
+    let value = match args.first() {
+        Some(value) => value,
+        None => {
+            return Err(VMError::Message(
+                ErrorKind::Type,
+                "Object.getPrototypeOf expects a value".to_string(),
+            ))
+        }
+    };
+
+    match interpreter.prototype_of(value) {
+        Some(prototype) => Ok(Value::Object(prototype)),
+        None => Ok(Value::Nothing),
+    }
+}
+
+fn object_keys(interpreter: &mut Interpreter, _base: Option<Value>, args: Vec<Value>) -> VMResult {
+    match args.first() {
+        Some(Value::Object(ref object)) => {
+            let keys = object
+                .borrow()
+                .keys()
+                .iter()
+                .map(|key| Value::String(key.clone()))
+                .collect();
+            Ok(Value::Array(interpreter.heap.array(keys)?))
+        }
+        _ => Err(VMError::Message(
+            ErrorKind::Type,
+            "Object.keys expects an object".to_string(),
+        )),
+    }
+}
+
+// Prints the bytecode of a function compiled for the VM.
+pub fn disassemble_function(
+    interpreter: &mut Interpreter,
+    _base: Option<Value>,
+    args: Vec<Value>,
+) -> VMResult {
+    let closure = match args.first() {
+        Some(Value::Closure(ref closure)) => closure.clone(),
+        Some(Value::BoundMethod(ref bound)) => bound.method.clone(),
+        _ => {
+            return Err(VMError::Message(
+                ErrorKind::Type,
+                "disassemble expects a function compiled for the VM".to_string(),
+            ))
+        }
+    };
+
+    interpreter.write_output(&disassemble(&closure.proto, false))?;
+    Ok(Value::Nothing)
+}
+
+// `inspect(value, depth)` shows the value tagged with its type, and the
+// contents of arrays and objects down to `depth` levels, 2 if it's left out.
+pub fn inspect(interpreter: &mut Interpreter, _base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let depth = match args.get(1) {
+        Some(Value::Number(depth)) if *depth >= 0 => *depth as usize,
+        None => 2,
+        _ => {
+            return Err(VMError::Message(
+                ErrorKind::Type,
+                "inspect expects a depth of 0 or more".to_string(),
+            ))
+        }
+    };
+
+    let value = args.first().cloned().unwrap_or(Value::Nothing);
+    let string = Value::String(value.inspect(depth));
+    interpreter.heap.reserve(value_size(&string))?;
+    Ok(string)
+}
+
+// Collects garbage now rather than when the heap next grows, and returns how
+// many values were freed.
+pub fn gc(interpreter: &mut Interpreter, _base: Option<Value>, _args: Vec<Value>) -> VMResult {
+    Ok(count(interpreter.collect_garbage()))
+}
+
+// Counts too big for a number, like the bytes of a heap past 2GB, show as
+// the biggest one.
+fn count(n: usize) -> Value {
+    Value::Number(i32::try_from(n).unwrap_or(i32::MAX))
+}
+
+pub fn heap_stats(
+    interpreter: &mut Interpreter,
+    _base: Option<Value>,
+    _args: Vec<Value>,
+) -> VMResult {
+    let stats = interpreter.heap_stats();
+    let mut types = Object::new();
+    for (name, objects) in stats.types {
+        types.set(name.to_string(), count(objects));
+    }
+
+    let mut object = Object::new();
+    object.set("objects".to_string(), count(stats.objects));
+    object.set(
+        "types".to_string(),
+        Value::Object(interpreter.heap.object(types)?),
+    );
+    object.set("bytes".to_string(), count(stats.bytes));
+    object.set("collections".to_string(), count(stats.collections));
+    object.set("freed".to_string(), count(stats.freed));
+    Ok(Value::Object(interpreter.heap.object(object)?))
+}
+
+// The global `Object`.
+pub fn object_constructor() -> Object {
+    let mut object = Object::new();
+    object.set("create".to_string(), Value::NativeFunction(object_create));
+    object.set("keys".to_string(), Value::NativeFunction(object_keys));
+    object.set(
+        "getPrototypeOf".to_string(),
+        Value::NativeFunction(object_get_prototype_of),
+    );
+    object
+}
+
+const ARRAY_METHODS: &[Method] = &[
+    ("push", array_push),
+    ("pop", array_pop),
+    ("shift", array_shift),
+    ("unshift", array_unshift),
+    ("slice", array_slice),
+    ("splice", array_splice),
+    ("concat", array_concat),
+    ("indexOf", array_index_of),
+    ("includes", array_includes),
+    ("reverse", array_reverse),
+    ("join", array_join),
+    ("map", array_map),
+    ("filter", array_filter),
+    ("reduce", array_reduce),
+    ("forEach", array_for_each),
+    ("find", array_find),
+    ("some", array_some),
+    ("every", array_every),
+    ("sort", array_sort),
+];
+
+const STRING_METHODS: &[Method] = &[
+    ("substring", string_substring),
+    ("indexOf", string_index_of),
+    ("split", string_split),
+    ("trim", string_trim),
+    ("toUpperCase", string_to_upper_case),
+    ("toLowerCase", string_to_lower_case),
+    ("replace", string_replace),
+    ("startsWith", string_starts_with),
+    ("endsWith", string_ends_with),
+    ("chars", string_chars),
+];
+
+pub fn array_prototype() -> Object {
+    prototype(ARRAY_METHODS)
+}
+
+pub fn string_prototype() -> Object {
+    prototype(STRING_METHODS)
+}
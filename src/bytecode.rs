@@ -0,0 +1,504 @@
+use std::convert::TryInto;
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, Constant, FunctionProto, OpCode};
+use crate::scanner::Position;
+
+// Layout of a .loxc file, all integers little-endian:
+//
+//   magic     b"LOXC"
+//   version   u16, bumped whenever the instruction set or layout changes
+//   count     u32, then that many functions. Functions come after the
+//             functions they define, so the script is the last one.
+//
+// and every function:
+//
+//   name       u8 flag, then a string if it's 1
+//   arity      u32
+//   upvalues   u32
+//   code       u32 length, then the bytes
+//   constants  u32 count, then a u8 tag each: 0 and an i32 for a number,
+//              1 and a string, 2 and the u32 index of a function
+//   positions  u32 count, then (offset, line, column) as three u32s
+//
+// Strings are a u32 length followed by UTF-8.
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u16 = 4;
+
+pub fn is_compiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+struct Writer {
+    out: Vec<u8>,
+    functions: u32,
+}
+
+impl Writer {
+    fn u32(&mut self, n: usize) {
+        self.out.extend_from_slice(&(n as u32).to_le_bytes());
+    }
+
+    fn string(&mut self, string: &str) {
+        self.u32(string.len());
+        self.out.extend_from_slice(string.as_bytes());
+    }
+
+    // Writes the functions defined in `proto` and then `proto` itself,
+    // returning its index.
+    fn function(&mut self, proto: &FunctionProto) -> u32 {
+        let chunk = &proto.chunk;
+        let functions: Vec<Option<u32>> = chunk
+            .constants
+            .iter()
+            .map(|constant| match constant {
+                Constant::Function(ref function) => Some(self.function(function)),
+                _ => None,
+            })
+            .collect();
+
+        match proto.name {
+            Some(ref name) => {
+                self.out.push(1);
+                self.string(name);
+            }
+            None => self.out.push(0),
+        }
+        self.u32(proto.arity);
+        self.u32(proto.upvalue_count);
+
+        self.u32(chunk.code.len());
+        self.out.extend_from_slice(&chunk.code);
+
+        self.u32(chunk.constants.len());
+        for (constant, function) in chunk.constants.iter().zip(functions) {
+            match constant {
+                Constant::Number(n) => {
+                    self.out.push(0);
+                    self.out.extend_from_slice(&n.to_le_bytes());
+                }
+                Constant::String(ref string) => {
+                    self.out.push(1);
+                    self.string(string);
+                }
+                Constant::Function(_) => {
+                    self.out.push(2);
+                    self.u32(function.unwrap() as usize);
+                }
+            }
+        }
+
+        self.u32(chunk.positions.len());
+        for (offset, position) in chunk.positions.iter() {
+            self.u32(*offset);
+            self.u32(position.line);
+            self.u32(position.column);
+        }
+
+        self.functions += 1;
+        self.functions - 1
+    }
+}
+
+pub fn write(script: &FunctionProto) -> Vec<u8> {
+    let mut writer = Writer {
+        out: Vec::new(),
+        functions: 0,
+    };
+    writer.function(script);
+
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&writer.functions.to_le_bytes());
+    out.extend(writer.out);
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self.offset.checked_add(count);
+        match end.and_then(|end| self.bytes.get(self.offset..end)) {
+            Some(bytes) => {
+                self.offset += count;
+                Ok(bytes)
+            }
+            None => Err("truncated bytecode file".to_string()),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        let bytes = self.bytes(4)?.try_into().unwrap();
+        Ok(u32::from_le_bytes(bytes) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = self.u32()?;
+        let bytes = self.bytes(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| "invalid string in bytecode file".to_string())
+    }
+
+    fn function(&mut self, functions: &[Rc<FunctionProto>]) -> Result<FunctionProto, String> {
+        let name = match self.u8()? {
+            0 => None,
+            _ => Some(self.string()?),
+        };
+        let arity = self.u32()?;
+        let upvalue_count = self.u32()?;
+
+        let length = self.u32()?;
+        let code = self.bytes(length)?.to_vec();
+
+        let count = self.u32()?;
+        let mut constants = Vec::new();
+        for _ in 0..count {
+            let constant = match self.u8()? {
+                0 => Constant::Number(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap())),
+                1 => Constant::String(self.string()?),
+                2 => match functions.get(self.u32()?) {
+                    Some(function) => Constant::Function(function.clone()),
+                    None => return Err("invalid function reference in bytecode file".to_string()),
+                },
+                tag => return Err(format!("invalid constant tag {} in bytecode file", tag)),
+            };
+            constants.push(constant);
+        }
+
+        let count = self.u32()?;
+        let mut positions = Vec::new();
+        for _ in 0..count {
+            let offset = self.u32()?;
+            let line = self.u32()?;
+            let column = self.u32()?;
+            positions.push((offset, Position { line, column }));
+        }
+
+        let proto = FunctionProto {
+            name,
+            arity,
+            upvalue_count,
+            chunk: Chunk {
+                code,
+                constants,
+                positions,
+            },
+        };
+        verify(&proto)?;
+        Ok(proto)
+    }
+}
+
+// Checks the code of a function so that running it can't make the VM read
+// past the code, the constant pool, the upvalues or the stack:
+//
+// - every instruction is complete and its constants are of the right kind,
+// - jumps land on the start of an instruction, and no path runs off the end
+//   of the code,
+// - upvalue indices are in range, and
+// - every instruction finds the values it pops and the locals it reads on
+//   the stack, which holds the same number of values whichever way the
+//   instruction is reached, and
+// - every handler pushed is popped before the function returns, so none is
+//   left to send a later exception into code that isn't running.
+fn verify(proto: &FunctionProto) -> Result<(), String> {
+    let invalid = || {
+        let name = proto.name.as_deref().unwrap_or("<script>");
+        Err(format!(
+            "invalid code in function {} of bytecode file",
+            name
+        ))
+    };
+
+    let lengths = match decode(proto) {
+        Some(lengths) => lengths,
+        None => return invalid(),
+    };
+    if !check_stack(proto, &lengths) {
+        return invalid();
+    }
+    Ok(())
+}
+
+// Checks each instruction on its own. Returns the length of the instruction
+// starting at each offset, or 0 where none starts.
+fn decode(proto: &FunctionProto) -> Option<Vec<usize>> {
+    let chunk = &proto.chunk;
+    let mut lengths = vec![0; chunk.code.len()];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = OpCode::from_byte(chunk.code[offset])?;
+        let operands = match op {
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call
+            | OpCode::CallMethod
+            | OpCode::TailCall
+            | OpCode::TailCallMethod => 1,
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::GetSuper
+            | OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::JumpIfFalseOrPop
+            | OpCode::JumpIfTrueOrPop
+            | OpCode::Loop
+            | OpCode::Array
+            | OpCode::Object
+            | OpCode::PushHandler
+            | OpCode::Closure => 2,
+            OpCode::Class => 4,
+            _ => 0,
+        };
+        if offset + operands >= chunk.code.len() {
+            return None;
+        }
+
+        let constant = || chunk.constants.get(chunk.read_u16(offset + 1) as usize);
+        let mut length = 1 + operands;
+        match op {
+            OpCode::Constant => match constant() {
+                Some(Constant::Number(_)) | Some(Constant::String(_)) => {}
+                _ => return None,
+            },
+            OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::GetSuper
+            | OpCode::Class => match constant() {
+                Some(Constant::String(_)) => {}
+                _ => return None,
+            },
+            OpCode::Closure => match constant() {
+                Some(Constant::Function(ref function)) => {
+                    length += 2 * function.upvalue_count;
+                    if offset + length > chunk.code.len() {
+                        return None;
+                    }
+                    // Upvalues of the enclosing function have to exist.
+                    // Captured locals are checked with the stack.
+                    for i in 0..function.upvalue_count {
+                        let index = chunk.code[offset + 4 + 2 * i] as usize;
+                        match chunk.code[offset + 3 + 2 * i] {
+                            1 => {}
+                            0 if index < proto.upvalue_count => {}
+                            _ => return None,
+                        }
+                    }
+                }
+                _ => return None,
+            },
+            OpCode::GetUpvalue | OpCode::SetUpvalue
+                if chunk.code[offset + 1] as usize >= proto.upvalue_count =>
+            {
+                return None
+            }
+            _ => {}
+        }
+
+        lengths[offset] = length;
+        offset += length;
+    }
+    Some(lengths)
+}
+
+// How many values are on the stack above the frame's base, and how many
+// handlers the function has pushed, when an instruction runs.
+#[derive(Clone, Copy, PartialEq)]
+struct State {
+    height: usize,
+    handlers: usize,
+}
+
+// Records that the instruction at `offset` is reached in `state`. It has to
+// start an instruction, and be reached in the same state every time.
+fn reach(
+    offset: usize,
+    state: State,
+    lengths: &[usize],
+    states: &mut [Option<State>],
+    pending: &mut Vec<usize>,
+) -> bool {
+    if lengths.get(offset).copied().unwrap_or(0) == 0 {
+        return false;
+    }
+    match states[offset] {
+        Some(known) => known == state,
+        None => {
+            states[offset] = Some(state);
+            pending.push(offset);
+            true
+        }
+    }
+}
+
+// Follows every path through the code from the start of the function,
+// tracking the stack and the handlers.
+fn check_stack(proto: &FunctionProto, lengths: &[usize]) -> bool {
+    let chunk = &proto.chunk;
+    let mut states = vec![None; chunk.code.len()];
+    let mut pending = Vec::new();
+
+    // The callee and the parameters.
+    let start = State {
+        height: 1 + proto.arity,
+        handlers: 0,
+    };
+    if !reach(0, start, lengths, &mut states, &mut pending) {
+        return false;
+    }
+
+    while let Some(offset) = pending.pop() {
+        let State { height, handlers } = states[offset].unwrap();
+        let op = OpCode::from_byte(chunk.code[offset]).unwrap();
+        let byte = |n: usize| chunk.code[offset + n] as usize;
+        let u16_at = |n: usize| chunk.read_u16(offset + n) as usize;
+
+        // How many values the instruction needs on the stack, and how many
+        // it pops and pushes.
+        let (needs, pops, pushes) = match op {
+            OpCode::Constant
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetGlobal
+            | OpCode::GetUpvalue => (0, 0, 1),
+            OpCode::GetLocal => (byte(1) + 1, 0, 1),
+            OpCode::SetLocal => (byte(1) + 1, 0, 0),
+            OpCode::SetGlobal | OpCode::SetUpvalue => (1, 0, 0),
+            OpCode::Pop
+            | OpCode::DefineGlobal
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::JumpIfFalse
+            | OpCode::JumpIfFalseOrPop
+            | OpCode::JumpIfTrueOrPop
+            | OpCode::Return
+            | OpCode::Throw => (1, 1, 0),
+            OpCode::Not => (1, 1, 1),
+            OpCode::GetMethod => (2, 1, 1),
+            OpCode::Get
+            | OpCode::GetSuper
+            | OpCode::Equal
+            | OpCode::NotEqual
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply => (2, 2, 1),
+            OpCode::Set | OpCode::Slice => (3, 3, 1),
+            OpCode::SetSlice => (4, 4, 1),
+            OpCode::Call | OpCode::TailCall => (byte(1) + 1, byte(1) + 1, 1),
+            OpCode::CallMethod | OpCode::TailCallMethod => (byte(1) + 2, byte(1) + 2, 1),
+            OpCode::Array => (u16_at(1), u16_at(1), 1),
+            OpCode::Object => (2 * u16_at(1), 2 * u16_at(1), 1),
+            OpCode::Class => {
+                let values = 2 * byte(3) + byte(4).min(1);
+                (values, values, 1)
+            }
+            // Captured locals have to be on the stack, except that a local
+            // function can capture the slot it's about to be stored in.
+            OpCode::Closure => {
+                let captured = (0..(lengths[offset] - 3) / 2)
+                    .filter(|i| byte(3 + 2 * i) == 1)
+                    .map(|i| byte(4 + 2 * i))
+                    .max()
+                    .unwrap_or(0);
+                (captured, 0, 1)
+            }
+            OpCode::Jump | OpCode::Loop | OpCode::PushHandler | OpCode::PopHandler => (0, 0, 0),
+        };
+        if height < needs {
+            return false;
+        }
+
+        // Leaving the frame with a handler still pushed would leave it to
+        // catch exceptions for whatever runs next.
+        let handlers = match op {
+            OpCode::Return | OpCode::TailCall | OpCode::TailCallMethod if handlers > 0 => {
+                return false
+            }
+            OpCode::PushHandler => handlers + 1,
+            OpCode::PopHandler => match handlers.checked_sub(1) {
+                Some(handlers) => handlers,
+                None => return false,
+            },
+            _ => handlers,
+        };
+
+        let after = height - pops + pushes;
+        let next = offset + lengths[offset];
+        let mut reach = |offset: usize, height: usize, handlers: usize| {
+            let state = State { height, handlers };
+            reach(offset, state, lengths, &mut states, &mut pending)
+        };
+        let reached = match op {
+            OpCode::Return | OpCode::Throw => true,
+            OpCode::Jump => reach(next + u16_at(1), after, handlers),
+            OpCode::Loop => match next.checked_sub(u16_at(1)) {
+                Some(target) => reach(target, after, handlers),
+                None => false,
+            },
+            OpCode::JumpIfFalse => {
+                reach(next + u16_at(1), after, handlers) && reach(next, after, handlers)
+            }
+            // These jump keeping the value they looked at.
+            OpCode::JumpIfFalseOrPop | OpCode::JumpIfTrueOrPop => {
+                reach(next + u16_at(1), height, handlers) && reach(next, after, handlers)
+            }
+            // The handler runs with the stack as it is here, plus the
+            // exception, and without itself.
+            OpCode::PushHandler => {
+                reach(next + u16_at(1), height + 1, handlers - 1) && reach(next, after, handlers)
+            }
+            _ => reach(next, after, handlers),
+        };
+        if !reached {
+            return false;
+        }
+    }
+    true
+}
+
+pub fn read(bytes: &[u8]) -> Result<Rc<FunctionProto>, String> {
+    if !is_compiled(bytes) {
+        return Err("not a compiled lox file".to_string());
+    }
+
+    let mut reader = Reader { bytes, offset: 4 };
+    let version = u16::from_le_bytes(reader.bytes(2)?.try_into().unwrap());
+    if version != VERSION {
+        return Err(format!(
+            "bytecode file has version {}, but this interpreter only runs version {}; recompile the script",
+            version, VERSION
+        ));
+    }
+
+    let count = reader.u32()?;
+    let mut functions = Vec::new();
+    for _ in 0..count {
+        let function = reader.function(&functions)?;
+        functions.push(Rc::new(function));
+    }
+
+    if reader.offset != bytes.len() {
+        return Err("trailing data in bytecode file".to_string());
+    }
+    functions
+        .pop()
+        .ok_or_else(|| "bytecode file has no script".to_string())
+}
@@ -0,0 +1,210 @@
+use std::rc::Rc;
+
+use crate::scanner::Position;
+
+// Instructions are a single opcode byte followed by their operands. Local
+// slots, upvalue indices and argument counts are one byte, constant indices,
+// element counts and jump offsets are two bytes, big-endian.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum OpCode {
+    // constant index
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    // slot
+    GetLocal,
+    SetLocal,
+    // name constant
+    GetGlobal,
+    SetGlobal,
+    DefineGlobal,
+    // upvalue index
+    GetUpvalue,
+    SetUpvalue,
+    // [base, key] -> value
+    Get,
+    // [base, key] -> [base, value], the base stays for CallMethod.
+    GetMethod,
+    // [base, key, value] -> value
+    Set,
+    // [base, start, end] -> slice, where nil bounds were left out
+    Slice,
+    // [base, start, end, value] -> value
+    SetSlice,
+    // name constant; [this, superclass] -> bound method
+    GetSuper,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    // [value] -> whether it's falsy
+    Not,
+    Print,
+    // forward offset
+    Jump,
+    // forward offset, pops the condition
+    JumpIfFalse,
+    // forward offset; jumps keeping the value if it's falsy, pops it if not
+    JumpIfFalseOrPop,
+    // forward offset; jumps keeping the value if it's truthy, pops it if not
+    JumpIfTrueOrPop,
+    // backward offset
+    Loop,
+    // argument count; [callee, args...] -> result
+    Call,
+    // argument count; [base, callee, args...] -> result
+    CallMethod,
+    // Like Call and CallMethod, but a script function called replaces the
+    // current frame. Followed by a Return for everything else.
+    TailCall,
+    TailCallMethod,
+    // function constant, then an (is_local, index) byte pair per upvalue
+    Closure,
+    CloseUpvalue,
+    Return,
+    // element count
+    Array,
+    // field count; [name, value, ...] -> object
+    Object,
+    // name constant, method count, has superclass;
+    // [superclass?, name, method, ...] -> class
+    Class,
+    Throw,
+    // forward offset to the handler
+    PushHandler,
+    PopHandler,
+}
+
+const OPCODES: [OpCode; 47] = [
+    OpCode::Constant,
+    OpCode::Nil,
+    OpCode::True,
+    OpCode::False,
+    OpCode::Pop,
+    OpCode::GetLocal,
+    OpCode::SetLocal,
+    OpCode::GetGlobal,
+    OpCode::SetGlobal,
+    OpCode::DefineGlobal,
+    OpCode::GetUpvalue,
+    OpCode::SetUpvalue,
+    OpCode::Get,
+    OpCode::GetMethod,
+    OpCode::Set,
+    OpCode::Slice,
+    OpCode::SetSlice,
+    OpCode::GetSuper,
+    OpCode::Equal,
+    OpCode::NotEqual,
+    OpCode::Greater,
+    OpCode::GreaterEqual,
+    OpCode::Less,
+    OpCode::LessEqual,
+    OpCode::Add,
+    OpCode::Subtract,
+    OpCode::Multiply,
+    OpCode::Not,
+    OpCode::Print,
+    OpCode::Jump,
+    OpCode::JumpIfFalse,
+    OpCode::JumpIfFalseOrPop,
+    OpCode::JumpIfTrueOrPop,
+    OpCode::Loop,
+    OpCode::Call,
+    OpCode::CallMethod,
+    OpCode::TailCall,
+    OpCode::TailCallMethod,
+    OpCode::Closure,
+    OpCode::CloseUpvalue,
+    OpCode::Return,
+    OpCode::Array,
+    OpCode::Object,
+    OpCode::Class,
+    OpCode::Throw,
+    OpCode::PushHandler,
+    OpCode::PopHandler,
+];
+
+impl OpCode {
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        OPCODES.get(byte as usize).copied()
+    }
+}
+
+#[derive(Debug)]
+pub enum Constant {
+    Number(i32),
+    String(String),
+    Function(Rc<FunctionProto>),
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Constant>,
+    // The source position of the code from each offset on, in order of
+    // offset. Only changes of position get an entry.
+    pub positions: Vec<(usize, Position)>,
+}
+
+impl Chunk {
+    pub fn write(&mut self, byte: u8, position: Position) {
+        if self.positions.last().map(|(_, last)| *last) != Some(position) {
+            self.positions.push((self.code.len(), position));
+        }
+        self.code.push(byte);
+    }
+
+    pub fn position_at(&self, offset: usize) -> Position {
+        let index = self
+            .positions
+            .partition_point(|(start, _)| *start <= offset);
+        match index {
+            0 => Position::default(),
+            _ => self.positions[index - 1].1,
+        }
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    // Reuses an existing number or string constant when there is one.
+    pub fn add_constant(&mut self, constant: Constant) -> Result<u16, String> {
+        let existing = self.constants.iter().position(|c| match (c, &constant) {
+            (Constant::Number(a), Constant::Number(b)) => a == b,
+            (Constant::String(a), Constant::String(b)) => a == b,
+            _ => false,
+        });
+
+        let index = match existing {
+            Some(index) => index,
+            None => {
+                self.constants.push(constant);
+                self.constants.len() - 1
+            }
+        };
+
+        if index > u16::MAX as usize {
+            return Err("too many constants in one function".to_string());
+        }
+        Ok(index as u16)
+    }
+}
+
+// A compiled function: the code plus what the VM needs to call it.
+#[derive(Debug, Default)]
+pub struct FunctionProto {
+    pub name: Option<String>,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+}
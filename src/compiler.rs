@@ -0,0 +1,858 @@
+use std::rc::Rc;
+
+use crate::chunk::{Constant, FunctionProto, OpCode};
+use crate::parser::{Expr, Node};
+use crate::scanner::Position;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+}
+
+struct Local {
+    name: String,
+    depth: usize,
+    captured: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Upvalue {
+    index: u8,
+    is_local: bool,
+}
+
+struct Loop {
+    // The number of locals and active try statements outside the loop.
+    locals: usize,
+    tries: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// A try statement whose handler is pushed. Leaving it by return, break or
+// continue has to pop the handler and run the finally block on the way out.
+struct Try<'a> {
+    finally: Option<&'a Node>,
+    locals: usize,
+    loops: usize,
+}
+
+struct FunctionState<'a> {
+    proto: FunctionProto,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+    tries: Vec<Try<'a>>,
+    // Whether statements record their value in slot 0, which the script
+    // returns as its result like the tree-walker does.
+    completion: bool,
+}
+
+impl<'a> FunctionState<'a> {
+    fn new(name: Option<&str>, kind: FunctionKind) -> Self {
+        // Slot 0 holds the callee, the receiver of a method or the
+        // script's completion value.
+        let slot_zero = if kind == FunctionKind::Method {
+            "this"
+        } else {
+            ""
+        };
+
+        FunctionState {
+            proto: FunctionProto {
+                name: name.map(|name| name.to_string()),
+                ..FunctionProto::default()
+            },
+            locals: vec![Local {
+                name: slot_zero.to_string(),
+                depth: 0,
+                captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: if kind == FunctionKind::Script { 0 } else { 1 },
+            loops: Vec::new(),
+            tries: Vec::new(),
+            completion: kind == FunctionKind::Script,
+        }
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<u8> {
+        self.locals
+            .iter()
+            .rposition(|local| local.name == name)
+            .map(|slot| slot as u8)
+    }
+}
+
+struct Compiler<'a> {
+    functions: Vec<FunctionState<'a>>,
+    // Where the code being emitted comes from.
+    position: Position,
+}
+
+// Compiles a resolved script into the function the VM runs. Scoping follows
+// the tree-walker: the top level declares globals, everything else locals.
+pub fn compile(node: &Node) -> Result<Rc<FunctionProto>, String> {
+    let mut compiler = Compiler {
+        functions: vec![FunctionState::new(None, FunctionKind::Script)],
+        position: Position { line: 1, column: 1 },
+    };
+
+    compiler.emit(OpCode::Nil);
+    compiler.emit_with_byte(OpCode::SetLocal, 0);
+    compiler.emit(OpCode::Pop);
+    compiler.statement(node)?;
+    compiler.emit_with_byte(OpCode::GetLocal, 0);
+    compiler.emit(OpCode::Return);
+
+    let state = compiler.functions.pop().unwrap();
+    Ok(Rc::new(state.proto))
+}
+
+impl<'a> Compiler<'a> {
+    fn state(&mut self) -> &mut FunctionState<'a> {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let position = self.position;
+        self.state().proto.chunk.write(byte, position);
+    }
+
+    fn emit(&mut self, op: OpCode) {
+        self.emit_byte(op as u8);
+    }
+
+    fn emit_with_byte(&mut self, op: OpCode, byte: u8) {
+        self.emit(op);
+        self.emit_byte(byte);
+    }
+
+    fn emit_with_u16(&mut self, op: OpCode, operand: u16) {
+        self.emit(op);
+        for byte in operand.to_be_bytes().iter() {
+            self.emit_byte(*byte);
+        }
+    }
+
+    fn code_len(&mut self) -> usize {
+        self.state().proto.chunk.code.len()
+    }
+
+    fn constant(&mut self, constant: Constant) -> Result<u16, String> {
+        self.state().proto.chunk.add_constant(constant)
+    }
+
+    fn name_constant(&mut self, name: &str) -> Result<u16, String> {
+        self.constant(Constant::String(name.to_string()))
+    }
+
+    // Emits a forward jump and returns the offset of its operand for patching.
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_with_u16(op, u16::MAX);
+        self.code_len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) -> Result<(), String> {
+        let jump = self.code_len() - offset - 2;
+        if jump > u16::MAX as usize {
+            return Err("too much code to jump over".to_string());
+        }
+
+        let bytes = (jump as u16).to_be_bytes();
+        let code = &mut self.state().proto.chunk.code;
+        code[offset] = bytes[0];
+        code[offset + 1] = bytes[1];
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, start: usize) -> Result<(), String> {
+        let jump = self.code_len() + 3 - start;
+        if jump > u16::MAX as usize {
+            return Err("loop body too large".to_string());
+        }
+
+        self.emit_with_u16(OpCode::Loop, jump as u16);
+        Ok(())
+    }
+
+    fn count(&self, count: usize, max: usize, what: &str) -> Result<usize, String> {
+        if count > max {
+            return Err(format!("too many {}", what));
+        }
+        Ok(count)
+    }
+
+    // Records the value on top of the stack as the script's result.
+    fn complete(&mut self) {
+        if self.state().completion {
+            self.emit_with_byte(OpCode::SetLocal, 0);
+        }
+    }
+
+    fn complete_nil(&mut self) {
+        if self.state().completion {
+            self.emit(OpCode::Nil);
+            self.complete();
+            self.emit(OpCode::Pop);
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.state();
+        state.scope_depth -= 1;
+
+        let depth = state.scope_depth;
+        while let Some(local) = self.state().locals.pop() {
+            if local.depth <= depth {
+                self.state().locals.push(local);
+                break;
+            }
+            self.pop_local(&local);
+        }
+    }
+
+    fn pop_local(&mut self, local: &Local) {
+        if local.captured {
+            self.emit(OpCode::CloseUpvalue);
+        } else {
+            self.emit(OpCode::Pop);
+        }
+    }
+
+    // Pops the locals above `count` without forgetting them, for jumps
+    // out of their scope. Code after the jump might still capture them, so
+    // they're always closed.
+    fn discard_locals(&mut self, count: usize) {
+        for _ in count..self.state().locals.len() {
+            self.emit(OpCode::CloseUpvalue);
+        }
+    }
+
+    // Declares a local for the value on top of the stack.
+    fn add_local(&mut self, name: &str) -> Result<u8, String> {
+        let state = self.state();
+        if state.locals.len() > u8::MAX as usize {
+            return Err("too many local variables in function".to_string());
+        }
+
+        let depth = state.scope_depth;
+        state.locals.push(Local {
+            name: name.to_string(),
+            depth,
+            captured: false,
+        });
+        Ok((state.locals.len() - 1) as u8)
+    }
+
+    fn is_global_scope(&mut self) -> bool {
+        self.state().scope_depth == 0
+    }
+
+    fn add_upvalue(&mut self, level: usize, index: u8, is_local: bool) -> Result<u8, String> {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &mut self.functions[level].upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return Ok(existing as u8);
+        }
+
+        if upvalues.len() > u8::MAX as usize {
+            return Err("too many closure variables in function".to_string());
+        }
+        upvalues.push(upvalue);
+        Ok((upvalues.len() - 1) as u8)
+    }
+
+    fn resolve_upvalue(&mut self, level: usize, name: &str) -> Result<Option<u8>, String> {
+        if level == 0 {
+            return Ok(None);
+        }
+
+        if let Some(slot) = self.functions[level - 1].resolve_local(name) {
+            self.functions[level - 1].locals[slot as usize].captured = true;
+            return Ok(Some(self.add_upvalue(level, slot, true)?));
+        }
+
+        match self.resolve_upvalue(level - 1, name)? {
+            Some(index) => Ok(Some(self.add_upvalue(level, index, false)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn variable(&mut self, name: &str, assign: bool) -> Result<(), String> {
+        if let Some(slot) = self.state().resolve_local(name) {
+            let op = if assign {
+                OpCode::SetLocal
+            } else {
+                OpCode::GetLocal
+            };
+            self.emit_with_byte(op, slot);
+            return Ok(());
+        }
+
+        let level = self.functions.len() - 1;
+        if let Some(index) = self.resolve_upvalue(level, name)? {
+            let op = if assign {
+                OpCode::SetUpvalue
+            } else {
+                OpCode::GetUpvalue
+            };
+            self.emit_with_byte(op, index);
+            return Ok(());
+        }
+
+        let constant = self.name_constant(name)?;
+        let op = if assign {
+            OpCode::SetGlobal
+        } else {
+            OpCode::GetGlobal
+        };
+        self.emit_with_u16(op, constant);
+        Ok(())
+    }
+
+    // Binds the value on top of the stack to a new global, or leaves it
+    // there as the local `slot` declared for it.
+    fn define_variable(&mut self, name: &str, slot: Option<u8>) -> Result<(), String> {
+        if slot.is_none() {
+            let constant = self.name_constant(name)?;
+            self.emit_with_u16(OpCode::DefineGlobal, constant);
+        }
+        Ok(())
+    }
+
+    fn declare_variable(&mut self, name: &str) -> Result<Option<u8>, String> {
+        if self.is_global_scope() {
+            Ok(None)
+        } else {
+            Ok(Some(self.add_local(name)?))
+        }
+    }
+
+    fn function(
+        &mut self,
+        name: Option<&str>,
+        parameters: &[String],
+        body: &'a Node,
+        kind: FunctionKind,
+    ) -> Result<(), String> {
+        let position = self.position;
+        self.functions.push(FunctionState::new(name, kind));
+        self.count(parameters.len(), u8::MAX as usize, "parameters")?;
+        self.state().proto.arity = parameters.len();
+        for parameter in parameters {
+            self.add_local(parameter)?;
+        }
+
+        self.statement(body)?;
+        // Falling off the end returns nil.
+        self.emit(OpCode::Nil);
+        self.emit(OpCode::Return);
+
+        let mut state = self.functions.pop().unwrap();
+        self.position = position;
+        state.proto.upvalue_count = state.upvalues.len();
+        let constant = self.constant(Constant::Function(Rc::new(state.proto)))?;
+        self.emit_with_u16(OpCode::Closure, constant);
+        for upvalue in state.upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+        Ok(())
+    }
+
+    // Compiles a finally block. Its value never becomes the result.
+    fn finally(&mut self, node: &'a Node) -> Result<(), String> {
+        let completion = self.state().completion;
+        self.state().completion = false;
+        let result = self.statement(node);
+        self.state().completion = completion;
+        result
+    }
+
+    // Pops the handlers of the try statements from `from` inwards and runs
+    // their finally blocks, innermost first, for a jump out of them.
+    fn exit_tries(&mut self, from: usize) -> Result<(), String> {
+        for index in (from..self.state().tries.len()).rev() {
+            self.emit(OpCode::PopHandler);
+
+            let (finally, locals, loops) = {
+                let outer = &self.state().tries[index];
+                (outer.finally, outer.locals, outer.loops)
+            };
+            let finally = match finally {
+                Some(finally) => finally,
+                None => continue,
+            };
+
+            // The finally block sees the scopes around the try statement,
+            // not the ones being jumped out of, whose locals are still on
+            // the stack underneath.
+            let state = self.state();
+            let tries = state.tries.split_off(index);
+            let loops = state.loops.split_off(loops);
+            let names: Vec<String> = state.locals[locals..]
+                .iter_mut()
+                .map(|local| std::mem::take(&mut local.name))
+                .collect();
+
+            let result = self.finally(finally);
+
+            let state = self.state();
+            state.tries.extend(tries);
+            state.loops.extend(loops);
+            for (local, name) in state.locals[locals..].iter_mut().zip(names) {
+                local.name = name;
+            }
+            result?;
+        }
+        Ok(())
+    }
+
+    fn jump_out_of_loop(&mut self, is_break: bool) -> Result<(), String> {
+        let (locals, tries) = match self.state().loops.last() {
+            Some(current) => (current.locals, current.tries),
+            None => return Err("break or continue outside of loop".to_string()),
+        };
+
+        self.exit_tries(tries)?;
+        self.discard_locals(locals);
+        let jump = self.emit_jump(OpCode::Jump);
+
+        let current = self.state().loops.last_mut().unwrap();
+        if is_break {
+            current.breaks.push(jump);
+        } else {
+            current.continues.push(jump);
+        }
+        Ok(())
+    }
+
+    fn statements(&mut self, statements: &'a [Node]) -> Result<(), String> {
+        if statements.is_empty() {
+            self.complete_nil();
+        }
+
+        for node in statements {
+            self.statement(node)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, node: &'a Node) -> Result<(), String> {
+        match *node {
+            Node::Statements(ref statements) => self.statements(statements)?,
+
+            Node::Block(ref statements) => {
+                self.begin_scope();
+                self.statements(statements)?;
+                self.end_scope();
+            }
+
+            Node::ExpressionStatement(ref expr, position) => {
+                self.position = position;
+                self.expr(expr)?;
+                self.complete();
+                self.emit(OpCode::Pop);
+            }
+
+            Node::Print(ref expr, position) => {
+                self.position = position;
+                self.expr(expr)?;
+                self.complete();
+                self.emit(OpCode::Print);
+            }
+
+            Node::Var(ref name, ref init, position) => {
+                self.position = position;
+                // Declared first, so closures in the initializer can refer
+                // to the variable.
+                let slot = self.declare_variable(name)?;
+                match init {
+                    Some(ref expr) => self.expr(expr)?,
+                    None => self.emit(OpCode::Nil),
+                }
+                self.complete();
+                self.define_variable(name, slot)?;
+            }
+
+            Node::Fun(ref name, ref parameters, ref body, position) => {
+                self.position = position;
+                let slot = self.declare_variable(name)?;
+                self.function(Some(name), parameters, body, FunctionKind::Function)?;
+                self.define_variable(name, slot)?;
+                self.complete_nil();
+            }
+
+            Node::Class(ref name, ref superclass, ref methods, position) => {
+                self.position = position;
+                // A local class gets its slot up front, below the scope
+                // binding `super`.
+                let slot = self.declare_variable(name)?;
+                if slot.is_some() {
+                    self.emit(OpCode::Nil);
+                }
+
+                if let Some(ref superclass) = superclass {
+                    self.expr(superclass)?;
+                    self.begin_scope();
+                    let slot = self.add_local("super")?;
+                    self.emit_with_byte(OpCode::GetLocal, slot);
+                }
+
+                let count = self.count(methods.len(), u8::MAX as usize, "methods in class")?;
+                for (method, parameters, body) in methods {
+                    let constant = self.name_constant(method)?;
+                    self.emit_with_u16(OpCode::Constant, constant);
+                    self.function(Some(method), parameters, body, FunctionKind::Method)?;
+                }
+
+                let constant = self.name_constant(name)?;
+                self.emit_with_u16(OpCode::Class, constant);
+                self.emit_byte(count as u8);
+                self.emit_byte(superclass.is_some() as u8);
+
+                match slot {
+                    Some(slot) => {
+                        self.emit_with_byte(OpCode::SetLocal, slot);
+                        self.emit(OpCode::Pop);
+                    }
+                    None => self.define_variable(name, None)?,
+                }
+
+                if superclass.is_some() {
+                    self.end_scope();
+                }
+                self.complete_nil();
+            }
+
+            Node::Return(ref expr, position) => {
+                self.position = position;
+                self.expr(expr)?;
+                if !self.state().tries.is_empty() {
+                    // The return value stays on the stack while the finally
+                    // blocks run.
+                    self.add_local("")?;
+                    self.exit_tries(0)?;
+                    self.state().locals.pop();
+                }
+                self.emit(OpCode::Return);
+            }
+
+            Node::TailCall(ref expr, position) => {
+                self.position = position;
+                self.call(expr, true)?;
+                self.emit(OpCode::Return);
+            }
+
+            Node::While(ref condition, ref body, ref update, position) => {
+                self.position = position;
+                let start = self.code_len();
+                self.expr(condition)?;
+                let exit = self.emit_jump(OpCode::JumpIfFalse);
+
+                let state = self.state();
+                let current = Loop {
+                    locals: state.locals.len(),
+                    tries: state.tries.len(),
+                    breaks: Vec::new(),
+                    continues: Vec::new(),
+                };
+                state.loops.push(current);
+
+                // The loop's value is always nil, whatever the body does.
+                let completion = state.completion;
+                state.completion = false;
+                let result = self.statement(body);
+                self.state().completion = completion;
+                let current = self.state().loops.pop().unwrap();
+                result?;
+
+                for jump in current.continues {
+                    self.patch_jump(jump)?;
+                }
+                self.position = position;
+                if let Some(ref update) = update {
+                    self.expr(update)?;
+                    self.emit(OpCode::Pop);
+                }
+                self.emit_loop(start)?;
+
+                self.patch_jump(exit)?;
+                for jump in current.breaks {
+                    self.patch_jump(jump)?;
+                }
+                self.complete_nil();
+            }
+
+            Node::Break => self.jump_out_of_loop(true)?,
+
+            Node::Continue => self.jump_out_of_loop(false)?,
+
+            Node::Throw(ref expr, position) => {
+                self.position = position;
+                self.expr(expr)?;
+                self.emit(OpCode::Throw);
+            }
+
+            Node::Try(ref block, ref catch, ref finally) => {
+                let finally = finally.as_ref().map(|finally| &**finally);
+                let mut exits = Vec::new();
+
+                let handler = self.emit_jump(OpCode::PushHandler);
+                self.enter_try(finally);
+                let result = self.statement(block);
+                self.state().tries.pop();
+                result?;
+                self.emit(OpCode::PopHandler);
+                if let Some(finally) = finally {
+                    self.finally(finally)?;
+                }
+                exits.push(self.emit_jump(OpCode::Jump));
+
+                // The handler starts with the exception on the stack.
+                self.patch_jump(handler)?;
+                self.begin_scope();
+                match catch {
+                    Some((ref name, ref handler)) => {
+                        self.add_local(name)?;
+
+                        // Exceptions from the catch block still run finally.
+                        let rethrow = match finally {
+                            Some(_) => {
+                                let rethrow = self.emit_jump(OpCode::PushHandler);
+                                self.enter_try(finally);
+                                Some(rethrow)
+                            }
+                            None => None,
+                        };
+
+                        let result = self.statement(handler);
+                        if let Some(rethrow) = rethrow {
+                            self.state().tries.pop();
+                            result?;
+                            self.emit(OpCode::PopHandler);
+                            self.end_scope();
+                            if let Some(finally) = finally {
+                                self.finally(finally)?;
+                            }
+                            exits.push(self.emit_jump(OpCode::Jump));
+
+                            self.patch_jump(rethrow)?;
+                            self.begin_scope();
+                            self.rethrow_after(finally)?;
+                        } else {
+                            result?;
+                        }
+                        self.end_scope();
+                    }
+                    None => {
+                        self.rethrow_after(finally)?;
+                        self.end_scope();
+                    }
+                }
+
+                for exit in exits {
+                    self.patch_jump(exit)?;
+                }
+            }
+
+            Node::If(ref condition, ref then, ref other, position) => {
+                self.position = position;
+                self.expr(condition)?;
+                let other_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.statement(then)?;
+                let end = self.emit_jump(OpCode::Jump);
+                self.patch_jump(other_jump)?;
+                self.statement(other)?;
+                self.patch_jump(end)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn enter_try(&mut self, finally: Option<&'a Node>) {
+        let state = self.state();
+        let current = Try {
+            finally,
+            locals: state.locals.len(),
+            loops: state.loops.len(),
+        };
+        state.tries.push(current);
+    }
+
+    // Runs the finally block for the exception on top of the stack, then
+    // throws it again.
+    fn rethrow_after(&mut self, finally: Option<&'a Node>) -> Result<(), String> {
+        let slot = self.add_local("")?;
+        if let Some(finally) = finally {
+            self.finally(finally)?;
+        }
+        self.emit_with_byte(OpCode::GetLocal, slot);
+        self.emit(OpCode::Throw);
+        Ok(())
+    }
+
+    fn binary(&mut self, l: &'a Expr, r: &'a Expr, op: OpCode) -> Result<(), String> {
+        self.expr(l)?;
+        self.expr(r)?;
+        self.emit(op);
+        Ok(())
+    }
+
+    // The left operand decides `and` and `or` when `jump` keeps it, otherwise
+    // it's popped and the right operand is the result.
+    fn logical(&mut self, l: &'a Expr, r: &'a Expr, jump: OpCode) -> Result<(), String> {
+        self.expr(l)?;
+        let end = self.emit_jump(jump);
+        self.expr(r)?;
+        self.patch_jump(end)
+    }
+
+    // The bounds of a slice, with nil for the ones left out.
+    fn bounds(
+        &mut self,
+        start: &'a Option<Box<Expr>>,
+        end: &'a Option<Box<Expr>>,
+    ) -> Result<(), String> {
+        for bound in [start, end] {
+            match bound {
+                Some(bound) => self.expr(bound)?,
+                None => self.emit(OpCode::Nil),
+            }
+        }
+        Ok(())
+    }
+
+    fn arguments(&mut self, arguments: &'a [Expr]) -> Result<u8, String> {
+        let count = self.count(arguments.len(), u8::MAX as usize, "arguments")?;
+        for argument in arguments {
+            self.expr(argument)?;
+        }
+        Ok(count as u8)
+    }
+
+    // Compiles a call or method call, leaving its result on the stack unless
+    // it's a tail call.
+    fn call(&mut self, expr: &'a Expr, tail: bool) -> Result<(), String> {
+        match *expr {
+            Expr::Call(ref callee, ref arguments, position) => {
+                self.expr(callee)?;
+                let count = self.arguments(arguments)?;
+                self.position = position;
+                let op = if tail { OpCode::TailCall } else { OpCode::Call };
+                self.emit_with_byte(op, count);
+            }
+            Expr::MethodCall(ref base, ref key, ref arguments, position) => {
+                self.expr(base)?;
+                self.expr(key)?;
+                self.emit(OpCode::GetMethod);
+                let count = self.arguments(arguments)?;
+                self.position = position;
+                let op = if tail {
+                    OpCode::TailCallMethod
+                } else {
+                    OpCode::CallMethod
+                };
+                self.emit_with_byte(op, count);
+            }
+            _ => self.expr(expr)?,
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: &'a Expr) -> Result<(), String> {
+        match *expr {
+            Expr::Eq(ref l, ref r) => self.binary(l, r, OpCode::Equal)?,
+            Expr::Ne(ref l, ref r) => self.binary(l, r, OpCode::NotEqual)?,
+            Expr::Greater(ref l, ref r) => self.binary(l, r, OpCode::Greater)?,
+            Expr::GreaterEqual(ref l, ref r) => self.binary(l, r, OpCode::GreaterEqual)?,
+            Expr::Less(ref l, ref r) => self.binary(l, r, OpCode::Less)?,
+            Expr::LessEqual(ref l, ref r) => self.binary(l, r, OpCode::LessEqual)?,
+            Expr::Plus(ref l, ref r) => self.binary(l, r, OpCode::Add)?,
+            Expr::Minus(ref l, ref r) => self.binary(l, r, OpCode::Subtract)?,
+            Expr::Multiply(ref l, ref r) => self.binary(l, r, OpCode::Multiply)?,
+            Expr::And(ref l, ref r) => self.logical(l, r, OpCode::JumpIfFalseOrPop)?,
+            Expr::Or(ref l, ref r) => self.logical(l, r, OpCode::JumpIfTrueOrPop)?,
+            Expr::Not(ref expr) => {
+                self.expr(expr)?;
+                self.emit(OpCode::Not);
+            }
+            Expr::Number(n) => {
+                let constant = self.constant(Constant::Number(n))?;
+                self.emit_with_u16(OpCode::Constant, constant);
+            }
+            Expr::String(ref string) => {
+                let constant = self.constant(Constant::String(string.clone()))?;
+                self.emit_with_u16(OpCode::Constant, constant);
+            }
+            Expr::Boolean(true) => self.emit(OpCode::True),
+            Expr::Boolean(false) => self.emit(OpCode::False),
+            Expr::Nil => self.emit(OpCode::Nil),
+            Expr::Call(..) | Expr::MethodCall(..) => self.call(expr, false)?,
+            Expr::Array(ref values) => {
+                let count = self.count(values.len(), u16::MAX as usize, "array elements")?;
+                for value in values {
+                    self.expr(value)?;
+                }
+                self.emit_with_u16(OpCode::Array, count as u16);
+            }
+            Expr::Object(ref fields) => {
+                let count = self.count(fields.len(), u16::MAX as usize, "object fields")?;
+                for (name, value) in fields {
+                    let constant = self.name_constant(name)?;
+                    self.emit_with_u16(OpCode::Constant, constant);
+                    self.expr(value)?;
+                }
+                self.emit_with_u16(OpCode::Object, count as u16);
+            }
+            Expr::Assign(ref name, _, ref value) => {
+                self.expr(value)?;
+                self.variable(name, true)?;
+            }
+            Expr::Identifier(ref name, _) => self.variable(name, false)?,
+            Expr::This(_) => self.variable("this", false)?,
+            Expr::Super(ref name, _) => {
+                self.variable("this", false)?;
+                self.variable("super", false)?;
+                let constant = self.name_constant(name)?;
+                self.emit_with_u16(OpCode::GetSuper, constant);
+            }
+            Expr::Function(ref parameters, ref body) => {
+                self.function(None, parameters, body, FunctionKind::Function)?;
+            }
+            Expr::Get(ref base, ref key) => {
+                self.expr(base)?;
+                self.expr(key)?;
+                self.emit(OpCode::Get);
+            }
+            Expr::Set(ref base, ref key, ref value) => {
+                self.expr(base)?;
+                self.expr(key)?;
+                self.expr(value)?;
+                self.emit(OpCode::Set);
+            }
+            Expr::Slice(ref base, ref start, ref end) => {
+                self.expr(base)?;
+                self.bounds(start, end)?;
+                self.emit(OpCode::Slice);
+            }
+            Expr::SetSlice(ref base, ref start, ref end, ref value) => {
+                self.expr(base)?;
+                self.bounds(start, end)?;
+                self.expr(value)?;
+                self.emit(OpCode::SetSlice);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt::Write;
+
+use crate::chunk::{Chunk, Constant, FunctionProto, OpCode};
+
+fn function_name(proto: &FunctionProto) -> &str {
+    match proto.name {
+        Some(ref name) => name,
+        None => "<anonymous>",
+    }
+}
+
+fn constant(chunk: &Chunk, index: usize) -> String {
+    match chunk.constants.get(index) {
+        Some(Constant::Number(n)) => n.to_string(),
+        Some(Constant::String(ref string)) => format!("{:?}", string),
+        Some(Constant::Function(ref proto)) => format!("<fn {}>", function_name(proto)),
+        None => "<invalid constant>".to_string(),
+    }
+}
+
+// Lists the instructions of the function and then of every function
+// defined in it, one line each: offset, source line ("|" when unchanged),
+// instruction and operands. Jumps show their target offset.
+pub fn disassemble(proto: &FunctionProto, script: bool) -> String {
+    let mut out = String::new();
+    let name = if script {
+        "<script>"
+    } else {
+        function_name(proto)
+    };
+    writeln!(out, "== {} ==", name).unwrap();
+
+    let chunk = &proto.chunk;
+    let mut offset = 0;
+    let mut last_line = None;
+    while offset < chunk.code.len() {
+        let line = chunk.position_at(offset).line;
+        if last_line == Some(line) {
+            write!(out, "{:04}    | ", offset).unwrap();
+        } else {
+            write!(out, "{:04} {:4} ", offset, line).unwrap();
+        }
+        last_line = Some(line);
+
+        offset = instruction(&mut out, chunk, offset);
+    }
+
+    for constant in chunk.constants.iter() {
+        if let Constant::Function(ref function) = constant {
+            out.push('\n');
+            out.push_str(&disassemble(function, false));
+        }
+    }
+    out
+}
+
+// Writes the instruction at `offset` and returns the offset of the next one.
+fn instruction(out: &mut String, chunk: &Chunk, offset: usize) -> usize {
+    let op = match OpCode::from_byte(chunk.code[offset]) {
+        Some(op) => op,
+        None => {
+            writeln!(out, "<invalid opcode {}>", chunk.code[offset]).unwrap();
+            return offset + 1;
+        }
+    };
+
+    let name = format!("{:?}", op);
+    let byte = |n: usize| chunk.code[offset + n] as usize;
+    let u16_at = |n: usize| chunk.read_u16(offset + n) as usize;
+
+    match op {
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::SetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::GetSuper => {
+            let index = u16_at(1);
+            writeln!(out, "{:<14} {:4} {}", name, index, constant(chunk, index)).unwrap();
+            offset + 3
+        }
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call
+        | OpCode::CallMethod
+        | OpCode::TailCall
+        | OpCode::TailCallMethod => {
+            writeln!(out, "{:<14} {:4}", name, byte(1)).unwrap();
+            offset + 2
+        }
+        OpCode::Array | OpCode::Object => {
+            writeln!(out, "{:<14} {:4}", name, u16_at(1)).unwrap();
+            offset + 3
+        }
+        OpCode::Jump
+        | OpCode::JumpIfFalse
+        | OpCode::JumpIfFalseOrPop
+        | OpCode::JumpIfTrueOrPop
+        | OpCode::PushHandler => {
+            let jump = u16_at(1);
+            writeln!(out, "{:<14} {:4} -> {:04}", name, jump, offset + 3 + jump).unwrap();
+            offset + 3
+        }
+        OpCode::Loop => {
+            let jump = u16_at(1);
+            writeln!(out, "{:<14} {:4} -> {:04}", name, jump, offset + 3 - jump).unwrap();
+            offset + 3
+        }
+        OpCode::Class => {
+            let index = u16_at(1);
+            writeln!(
+                out,
+                "{:<14} {:4} {} ({} methods{})",
+                name,
+                index,
+                constant(chunk, index),
+                byte(3),
+                if byte(4) == 1 { ", superclass" } else { "" }
+            )
+            .unwrap();
+            offset + 5
+        }
+        OpCode::Closure => {
+            let index = u16_at(1);
+            writeln!(out, "{:<14} {:4} {}", name, index, constant(chunk, index)).unwrap();
+
+            let count = match chunk.constants.get(index) {
+                Some(Constant::Function(ref proto)) => proto.upvalue_count,
+                _ => 0,
+            };
+            let mut next = offset + 3;
+            for _ in 0..count {
+                let kind = if chunk.code[next] == 1 {
+                    "local"
+                } else {
+                    "upvalue"
+                };
+                writeln!(out, "{:04}    |   {} {}", next, kind, chunk.code[next + 1]).unwrap();
+                next += 2;
+            }
+            next
+        }
+        _ => {
+            writeln!(out, "{}", name).unwrap();
+            offset + 1
+        }
+    }
+}
@@ -1,14 +1,27 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::mem;
 use std::rc::Rc;
 
-use crate::execute::{VMError, VMResult};
+use crate::execute::{ErrorKind, VMError, VMResult};
+use crate::gc::{address, trace_value, value_size};
 use crate::value::Value;
 
+// Globals live in the outermost environment and are looked up by name.
+// Every other environment holds locals in slots, in the order the resolver
+// assigned them, and they are accessed by (depth, slot).
 #[derive(Debug, Clone)]
 pub struct Environment {
     enclosing: Option<Rc<RefCell<Environment>>>,
     bindings: HashMap<String, Value>,
+    slots: Vec<Value>,
+}
+
+fn no_such_variable(name: &str) -> VMResult {
+    Err(VMError::Message(
+        ErrorKind::Reference,
+        format!("no such variable '{}'", name),
+    ))
 }
 
 impl Environment {
@@ -16,6 +29,7 @@ impl Environment {
         Environment {
             enclosing: None,
             bindings: HashMap::new(),
+            slots: Vec::new(),
         }
     }
 
@@ -23,6 +37,7 @@ impl Environment {
         Environment {
             enclosing: Some(env),
             bindings: HashMap::new(),
+            slots: Vec::new(),
         }
     }
 
@@ -30,28 +45,91 @@ impl Environment {
         self.bindings.insert(name, value);
     }
 
+    // Defines the next local slot.
+    pub fn push(&mut self, value: Value) {
+        self.slots.push(value);
+    }
+
+    // For declarations, which are globals at the top level and locals elsewhere.
+    pub fn declare(&mut self, name: &str, value: Value) {
+        match self.enclosing {
+            Some(_) => self.push(value),
+            None => self.define(name.to_string(), value),
+        }
+    }
+
     pub fn set(&mut self, name: String, value: Value) -> VMResult {
-        if self.bindings.contains_key(&name) {
-            self.bindings.insert(name, value.clone());
-            return Ok(value);
+        match self.bindings.get_mut(&name) {
+            Some(binding) => {
+                *binding = value.clone();
+                Ok(value)
+            }
+            None => no_such_variable(&name),
         }
+    }
 
-        if let Some(ref env) = self.enclosing {
-            return env.borrow_mut().set(name, value);
+    pub fn get(&self, name: &str) -> VMResult {
+        match self.bindings.get(name) {
+            Some(val) => Ok(val.clone()),
+            None => no_such_variable(name),
         }
+    }
 
-        Err(VMError::Message(format!("no such variable '{}'", name)))
+    // Assigns the local in `slot` of the environment `depth` scopes up.
+    // The name is only used for reporting errors.
+    pub fn set_at(&mut self, depth: usize, slot: usize, name: &str, value: Value) -> VMResult {
+        if depth > 0 {
+            return match self.enclosing {
+                Some(ref env) => env.borrow_mut().set_at(depth - 1, slot, name, value),
+                None => no_such_variable(name),
+            };
+        }
+
+        match self.slots.get_mut(slot) {
+            Some(local) => {
+                *local = value.clone();
+                Ok(value)
+            }
+            None => no_such_variable(name),
+        }
     }
 
-    pub fn get(&self, name: &str) -> VMResult {
-        if let Some(val) = self.bindings.get(name) {
-            return Ok(val.clone());
+    // Reads the local in `slot` of the environment `depth` scopes up.
+    pub fn get_at(&self, depth: usize, slot: usize, name: &str) -> VMResult {
+        if depth > 0 {
+            return match self.enclosing {
+                Some(ref env) => env.borrow().get_at(depth - 1, slot, name),
+                None => no_such_variable(name),
+            };
+        }
+
+        match self.slots.get(slot) {
+            Some(val) => Ok(val.clone()),
+            None => no_such_variable(name),
         }
+    }
 
+    pub fn trace(&self, visit: &mut dyn FnMut(*const ())) {
         if let Some(ref env) = self.enclosing {
-            return env.borrow().get(name);
+            visit(address(env));
         }
+        self.bindings
+            .values()
+            .chain(self.slots.iter())
+            .for_each(|value| trace_value(value, visit));
+    }
+
+    pub fn size(&self) -> usize {
+        let bindings: usize = self
+            .bindings
+            .iter()
+            .map(|(name, value)| name.len() + value_size(value))
+            .sum();
+        let slots: usize = self.slots.iter().map(value_size).sum();
+        mem::size_of::<Environment>() + bindings + slots
+    }
 
-        Err(VMError::Message(format!("no such variable '{}'", name)))
+    pub fn clear(&mut self) {
+        *self = Environment::new();
     }
 }
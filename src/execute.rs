@@ -1,302 +1,1051 @@
 use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
 use std::rc::Rc;
+use std::time::Instant;
 
+use crate::builtins;
 use crate::environment::Environment;
-use crate::object::Object;
+use crate::gc::{value_size, Heap, HeapStats};
+use crate::limits::{InterruptHandle, Limits};
+use crate::object::{Class, Object};
 use crate::parser::{Expr, Node};
+use crate::scanner::Position;
 use crate::value::Value;
+use crate::vm::{BoundMethod, Vm};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorKind {
+    Type,
+    Reference,
+    Range,
+    Memory,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Type => write!(f, "TypeError"),
+            ErrorKind::Reference => write!(f, "ReferenceError"),
+            ErrorKind::Range => write!(f, "RangeError"),
+            ErrorKind::Memory => write!(f, "MemoryError"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum VMError {
-    Message(String),
+    Message(ErrorKind, String),
+    Throw(Value),
+    // A limit set by the host was hit. Scripts can't catch it.
+    Interrupted(String),
+    // Writing to the output failed. Scripts can't catch this either.
+    Output(io::Error),
+    // An error along with the calls that were running when it was raised.
+    Traced(Box<VMError>, Vec<StackFrame>),
     Return(Value),
+    // Returning a call in tail position: the callee, base and arguments.
+    TailCall(Box<(Value, Option<Value>, Vec<Value>)>),
+    Break,
+    Continue,
 }
 
-pub type VMResult = Result<Value, VMError>;
+impl VMError {
+    // Attaches the stack trace to runtime errors and thrown values, unless
+    // they have one already.
+    pub fn traced(self, trace: impl FnOnce() -> Vec<StackFrame>) -> VMError {
+        match self {
+            VMError::Message(..) | VMError::Throw(_) => VMError::Traced(Box::new(self), trace()),
+            e => e,
+        }
+    }
+
+    pub fn is_catchable(&self) -> bool {
+        match self {
+            VMError::Message(..) | VMError::Throw(_) => true,
+            VMError::Traced(ref e, _) => e.is_catchable(),
+            _ => false,
+        }
+    }
 
-fn err(msg: &str) -> VMResult {
-    Err(VMError::Message(msg.to_string()))
+    // The value a catch clause binds: thrown values as they are, and
+    // runtime errors as an object with `kind`, `message` and `stack`.
+    pub fn into_exception(self, heap: &Heap) -> Result<Value, VMError> {
+        if !self.is_catchable() {
+            return Err(self);
+        }
+        let (e, trace) = match self {
+            VMError::Traced(e, trace) => (*e, trace),
+            e => (e, Vec::new()),
+        };
+        match e {
+            VMError::Message(kind, ref msg) => {
+                let mut object = Object::new();
+                object.set("kind".to_string(), Value::String(kind.to_string()));
+                object.set("message".to_string(), Value::String(msg.clone()));
+                let stack = format!("{}: {}{}", kind, msg, format_trace(&trace, None));
+                object.set("stack".to_string(), Value::String(stack));
+                Ok(Value::Object(heap.object(object)?))
+            }
+            VMError::Throw(ref value) => Ok(value.clone()),
+            e => Err(e),
+        }
+    }
 }
 
-fn array_push(base: Option<Value>, args: Vec<Value>) -> Value {
-    if let Some(Value::Array(ref array)) = base {
-        array.borrow_mut().extend(args)
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VMError::Message(kind, ref msg) => write!(f, "{}: {}", kind, msg),
+            VMError::Throw(ref value) => write!(f, "uncaught exception: {}", value),
+            VMError::Interrupted(ref reason) => write!(f, "execution interrupted: {}", reason),
+            VMError::Output(ref e) => write!(f, "writing output failed: {}", e),
+            VMError::Traced(ref e, ref trace) => write!(f, "{}{}", e, format_trace(trace, None)),
+            VMError::Return(_) | VMError::TailCall(..) => {
+                write!(f, "return outside of function")
+            }
+            VMError::Break => write!(f, "break outside of loop"),
+            VMError::Continue => write!(f, "continue outside of loop"),
+        }
     }
+}
+
+pub type VMResult = Result<Value, VMError>;
 
-    Value::Nothing
+// A call in a stack trace: the function and where in it execution was.
+#[derive(Debug, Clone)]
+pub struct StackFrame {
+    pub function: String,
+    pub position: Position,
 }
 
-fn get(base: Value, key: Value) -> VMResult {
-    if let Value::Array(ref array) = base {
-        match key {
-            Value::Number(n) => {
-                if n < 0 {
-                    return err("negative array index");
-                }
+// Only the innermost calls are listed in a stack trace.
+const TRACE_LENGTH: usize = 10;
 
-                match array.borrow().get(n as usize) {
-                    Some(v) => Ok(v.clone()),
-                    _ => err("array index of range"),
-                }
-            }
-            Value::String(ref string) if string == "length" => {
-                Ok(Value::Number(array.borrow().len() as i32))
-            }
-            Value::String(ref string) if string == "push" => Ok(Value::NativeFunction(array_push)),
-            _ => err("invalid key"),
+// Formats a stack trace, innermost call first, with a line per call like
+// `at fib (script.lox:20:12)`. Positions are only `line:column` without a
+// file name.
+pub fn format_trace(trace: &[StackFrame], file: Option<&str>) -> String {
+    let mut lines = String::new();
+    for frame in trace.iter().take(TRACE_LENGTH) {
+        let position = frame.position;
+        lines.push_str(&format!("\n    at {} (", frame.function));
+        if let Some(file) = file {
+            lines.push_str(&format!("{}:", file));
         }
-    } else if let Value::Object(ref object) = base {
-        if let Value::String(ref string) = key {
-            object.borrow().get(string.clone())
-        } else {
-            err("value lookup only with string key")
+        lines.push_str(&format!("{}:{})", position.line, position.column));
+    }
+    if trace.len() > TRACE_LENGTH {
+        lines.push_str(&format!("\n    ... {} more", trace.len() - TRACE_LENGTH));
+    }
+    lines
+}
+
+// The error for recursing deeper than the call depth limit.
+pub fn call_stack_exceeded() -> VMError {
+    VMError::Message(
+        ErrorKind::Range,
+        "maximum call stack size exceeded".to_string(),
+    )
+}
+
+pub fn err(kind: ErrorKind, msg: &str) -> VMResult {
+    Err(VMError::Message(kind, msg.to_string()))
+}
+
+// Returns a copy of the method whose scope has `this` bound to the instance.
+pub fn bind(heap: &Heap, method: Value, this: Value) -> VMResult {
+    match method {
+        Value::Function(ref name, ref parameters, ref body, ref scope) => {
+            let mut env = Environment::new_enclosing(scope.clone());
+            env.push(this);
+            Ok(Value::Function(
+                name.clone(),
+                parameters.clone(),
+                body.clone(),
+                heap.environment(env)?,
+            ))
         }
+        Value::Closure(ref closure) => Ok(Value::BoundMethod(heap.bound_method(BoundMethod {
+            receiver: this,
+            method: closure.clone(),
+        })?)),
+        _ => err(ErrorKind::Type, "expected function as method"),
+    }
+}
+
+// Looks up `name` on the superclass, bound to the current instance.
+pub fn bind_super(heap: &Heap, superclass: Value, this: Value, name: &str) -> VMResult {
+    let method = match superclass {
+        Value::Class(ref class) => class.find_method(name),
+        _ => None,
+    };
+
+    match method {
+        Some(method) => bind(heap, method, this),
+        None => Err(VMError::Message(
+            ErrorKind::Reference,
+            format!("no method named '{}' on super", name),
+        )),
+    }
+}
+
+// The binary operators, shared by both backends.
+
+// Where index `n` is in something `length` long. Negative indices count
+// back from the end, so -1 is the last element.
+fn index(n: i32, length: usize) -> Option<usize> {
+    let i = if n < 0 {
+        length as i64 + n as i64
     } else {
-        err("invalid base")
+        n as i64
+    };
+    if (0..length as i64).contains(&i) {
+        Some(i as usize)
+    } else {
+        None
     }
 }
 
-fn call(
-    callee: Value,
-    base: Option<Value>,
-    arguments: &Vec<Box<Expr>>,
-    env: &Rc<RefCell<Environment>>,
-) -> VMResult {
-    match callee {
-        Value::NativeFunction(ref fun) => {
-            let args: Result<Vec<Value>, _> = arguments
-                .iter()
-                .map(|arg| execute_expr(&arg, env))
-                .collect();
-
-            Ok(fun(base, args?))
+// The range a slice covers. Like indices, negative bounds count back from
+// the end; bounds past either end stop there, and a start after the end
+// gives an empty slice.
+pub fn slice_bounds(start: Value, end: Value, length: usize) -> Result<(usize, usize), VMError> {
+    let bound = |value: Value, default: usize| match value {
+        Value::Nothing => Ok(default),
+        Value::Number(n) if n < 0 => Ok((length as i64 + n as i64).max(0) as usize),
+        Value::Number(n) => Ok((n as usize).min(length)),
+        _ => Err(VMError::Message(
+            ErrorKind::Type,
+            "slice bounds must be numbers".to_string(),
+        )),
+    };
+    let start = bound(start, 0)?;
+    let end = bound(end, length)?;
+    Ok((start, end.max(start)))
+}
+
+pub fn equal(left: Value, right: Value) -> VMResult {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a == b)),
+        (Value::Nothing, Value::Nothing) => Ok(Value::Boolean(true)),
+        (Value::Nothing, _) | (_, Value::Nothing) => Ok(Value::Boolean(false)),
+        _ => err(ErrorKind::Type, "Unexpected Eq operands"),
+    }
+}
+
+pub fn not_equal(left: Value, right: Value) -> VMResult {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a != b)),
+        (Value::Nothing, Value::Nothing) => Ok(Value::Boolean(false)),
+        (Value::Nothing, _) | (_, Value::Nothing) => Ok(Value::Boolean(true)),
+        _ => err(ErrorKind::Type, "Unexpected Ne operands"),
+    }
+}
+
+pub fn greater(left: Value, right: Value) -> VMResult {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a > b)),
+        _ => err(ErrorKind::Type, "Unexpected > operands"),
+    }
+}
+
+pub fn greater_equal(left: Value, right: Value) -> VMResult {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a >= b)),
+        _ => err(ErrorKind::Type, "Unexpected >= operands"),
+    }
+}
+
+pub fn less(left: Value, right: Value) -> VMResult {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a < b)),
+        _ => err(ErrorKind::Type, "Unexpected < operands"),
+    }
+}
+
+pub fn less_equal(left: Value, right: Value) -> VMResult {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a <= b)),
+        _ => err(ErrorKind::Type, "Unexpected <= operands"),
+    }
+}
+
+pub fn add(left: Value, right: Value) -> VMResult {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+        _ => err(ErrorKind::Type, "Unexpected Plus operands"),
+    }
+}
+
+pub fn subtract(left: Value, right: Value) -> VMResult {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a - b)),
+        _ => err(ErrorKind::Type, "Unexpected Minus operands"),
+    }
+}
+
+pub fn multiply(left: Value, right: Value) -> VMResult {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * b)),
+        _ => err(ErrorKind::Type, "Unexpected Multiply operands"),
+    }
+}
+
+pub struct Interpreter {
+    globals: Rc<RefCell<Environment>>,
+    // Property lookups on arrays and strings fall back to these.
+    array_prototype: Rc<RefCell<Object>>,
+    string_prototype: Rc<RefCell<Object>>,
+    // State of the bytecode VM, see vm.rs.
+    pub vm: Vm,
+    pub heap: Heap,
+    pub limits: Limits,
+    // The calls the tree-walker is in, innermost last: the name of the
+    // function called and where it was called from.
+    calls: Vec<(String, Position)>,
+    // Where the tree-walker is in the function it's running: the statement
+    // or call it's at.
+    position: Position,
+    // Where print and println write to.
+    output: Box<dyn Write>,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter::with_output(Box::new(io::stdout()))
+    }
+
+    // An interpreter printing to `output` instead of stdout.
+    pub fn with_output(output: Box<dyn Write>) -> Self {
+        // There's no memory limit yet, so allocating can't fail.
+        let heap = Heap::new();
+        let globals = heap.environment(Environment::new()).unwrap();
+        globals.borrow_mut().define(
+            "Object".to_string(),
+            Value::Object(heap.object(builtins::object_constructor()).unwrap()),
+        );
+        globals.borrow_mut().define(
+            "disassemble".to_string(),
+            Value::NativeFunction(builtins::disassemble_function),
+        );
+
+        globals
+            .borrow_mut()
+            .define("gc".to_string(), Value::NativeFunction(builtins::gc));
+        globals.borrow_mut().define(
+            "inspect".to_string(),
+            Value::NativeFunction(builtins::inspect),
+        );
+        globals.borrow_mut().define(
+            "heapStats".to_string(),
+            Value::NativeFunction(builtins::heap_stats),
+        );
+
+        Interpreter {
+            globals,
+            array_prototype: heap.object(builtins::array_prototype()).unwrap(),
+            string_prototype: heap.object(builtins::string_prototype()).unwrap(),
+            vm: Vm::default(),
+            heap,
+            limits: Limits::default(),
+            calls: Vec::new(),
+            position: Position::default(),
+            output,
         }
-        Value::Function(ref parameters, ref body, ref scope) => {
-            let args: Result<Vec<Value>, _> = arguments
-                .iter()
-                .map(|arg| execute_expr(&arg, env))
-                .collect();
+    }
+
+    // Runs a garbage collection and returns how many values it freed.
+    pub fn collect_garbage(&self) -> usize {
+        self.heap.collect()
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    // Limits the memory scripts can allocate to about `bytes`. Allocating
+    // past it is a MemoryError.
+    pub fn set_memory_limit(&mut self, bytes: usize) {
+        self.heap.set_limit(Some(bytes));
+    }
 
-            // ToDo: argument count != paramter count
-            let local = Rc::new(RefCell::new(Environment::new_enclosing(scope.clone())));
-            for (name, arg) in parameters.iter().zip(args?) {
-                local.borrow_mut().define(name.clone(), arg);
+    // Stops each script run after this many steps: statements on the
+    // tree-walker, instructions on the VM.
+    pub fn set_step_limit(&mut self, steps: u64) {
+        self.limits.set_step_limit(steps);
+    }
+
+    // Recursing deeper than this is a RangeError.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.limits.max_call_depth = depth;
+    }
+
+    // Recursing so deep that scripts use more than this many bytes of the
+    // stack, counted from where run or run_script was called, is a
+    // RangeError too. Scripts run on the caller's thread, which has to have
+    // this much stack to spare and some more: natives and printing use a
+    // little past the last check. The default of 1MB suits threads with
+    // the usual 2MB of stack.
+    pub fn set_stack_limit(&mut self, bytes: usize) {
+        self.limits.stack_limit = bytes;
+    }
+
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.limits.set_deadline(deadline);
+    }
+
+    // A handle that stops the running script from another thread or a
+    // signal handler.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.limits.interrupt_handle()
+    }
+
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.globals.borrow_mut().define(name.to_string(), value);
+    }
+
+    pub fn get_global(&self, name: &str) -> VMResult {
+        self.globals.borrow().get(name)
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) -> VMResult {
+        self.globals.borrow_mut().set(name.to_string(), value)
+    }
+
+    pub fn run(&mut self, node: &Node) -> VMResult {
+        self.on_stack(|interpreter| {
+            interpreter.limits.start();
+            let globals = interpreter.globals.clone();
+            interpreter
+                .execute_node(node, &globals)
+                .map_err(|e| e.traced(|| interpreter.tree_trace()))
+        })
+    }
+
+    // Runs `f` with the stack scripts use counted from here.
+    pub fn on_stack(&mut self, f: impl FnOnce(&mut Interpreter) -> VMResult) -> VMResult {
+        let entered = self.limits.enter_stack();
+        let result = f(self);
+        if entered {
+            self.limits.leave_stack();
+        }
+        result
+    }
+
+    pub fn prototype_of(&self, value: &Value) -> Option<Rc<RefCell<Object>>> {
+        match *value {
+            Value::Array(_) => Some(self.array_prototype.clone()),
+            Value::String(_) => Some(self.string_prototype.clone()),
+            Value::Object(ref object) => object.borrow().prototype(),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, base: Value, key: Value) -> VMResult {
+        if let Value::Array(ref array) = base {
+            match key {
+                Value::Number(n) => {
+                    let array = array.borrow();
+                    match index(n, array.len()) {
+                        Some(i) => Ok(array[i].clone()),
+                        _ => err(ErrorKind::Range, "array index out of range"),
+                    }
+                }
+                Value::String(ref string) if string == "length" => {
+                    Ok(Value::Number(array.borrow().len() as i32))
+                }
+                Value::String(ref string) => self.array_prototype.borrow().get(string.clone()),
+                _ => err(ErrorKind::Type, "invalid key"),
             }
+        } else if let Value::String(ref string) = base {
+            // Strings are indexed by character, not by byte.
+            match key {
+                Value::Number(n) => {
+                    let i = index(n, string.chars().count());
+                    match i.and_then(|i| string.chars().nth(i)) {
+                        Some(c) => Ok(Value::String(c.to_string())),
+                        _ => err(ErrorKind::Range, "string index out of range"),
+                    }
+                }
+                Value::String(ref name) if name == "length" => {
+                    Ok(Value::Number(string.chars().count() as i32))
+                }
+                Value::String(ref name) => self.string_prototype.borrow().get(name.clone()),
+                _ => err(ErrorKind::Type, "invalid key"),
+            }
+        } else if let Value::Object(ref object) = base {
+            if let Value::String(ref string) = key {
+                // Fields shadow methods of the same name.
+                let method = {
+                    let object = object.borrow();
+                    match object.class() {
+                        Some(ref class) if !object.has(string) => class.find_method(string),
+                        _ => None,
+                    }
+                };
 
-            match execute_node(&body, &local) {
-                Err(VMError::Return(v)) => Ok(v.clone()),
-                e @ Err(_) => e,
-                Ok(_) => Ok(Value::Nothing), // No implicit return!
+                if let Some(method) = method {
+                    return bind(&self.heap, method, base.clone());
+                }
+
+                object.borrow().get(string.clone())
+            } else {
+                err(ErrorKind::Type, "value lookup only with string key")
             }
+        } else {
+            err(ErrorKind::Type, "invalid base")
         }
-        _ => err("expected function callee"),
     }
-}
 
-// Todo: This is probably going to require a different ownership story
-pub fn execute_node(node: &Box<Node>, env: &Rc<RefCell<Environment>>) -> VMResult {
-    match **node {
-        Node::Statements(ref statements) => {
-            let mut last = Value::Nothing;
-            for node in statements {
-                last = execute_node(&node, env)?;
+    pub fn set(&mut self, base: Value, key: Value, value: Value) -> VMResult {
+        match (base, key) {
+            (Value::Array(ref array), Value::Number(n)) => {
+                self.heap.reserve(value_size(&value))?;
+                let mut array = array.borrow_mut();
+                match index(n, array.len()) {
+                    Some(i) => array[i] = value.clone(),
+                    _ => return err(ErrorKind::Range, "array index out of range"),
+                }
+            }
+            (Value::Object(ref object), Value::String(ref string)) => {
+                self.heap.reserve(2 * string.len() + value_size(&value))?;
+                object.borrow_mut().set(string.clone(), value.clone());
             }
-            Ok(last)
+            _ => return err(ErrorKind::Type, "array only"),
         }
 
-        Node::ExpressionStatement(ref expr) => execute_expr(&expr, env),
+        Ok(value)
+    }
 
-        Node::Block(ref statements) => {
-            let block_scope = Rc::new(RefCell::new(Environment::new_enclosing(env.clone())));
-            let mut last = Value::Nothing;
-            for node in statements {
-                last = execute_node(&node, &block_scope)?;
+    // `base[start:end]`: a new array or string of the part from start up to
+    // but not including end. Nil bounds mean the start or end of base.
+    pub fn slice(&self, base: Value, start: Value, end: Value) -> VMResult {
+        match base {
+            Value::Array(ref array) => {
+                let part = {
+                    let array = array.borrow();
+                    let (start, end) = slice_bounds(start, end, array.len())?;
+                    array[start..end].to_vec()
+                };
+                Ok(Value::Array(self.heap.array(part)?))
             }
-            Ok(last)
+            Value::String(ref string) => {
+                let (start, end) = slice_bounds(start, end, string.chars().count())?;
+                let part: String = string.chars().skip(start).take(end - start).collect();
+                let part = Value::String(part);
+                self.heap.reserve(value_size(&part))?;
+                Ok(part)
+            }
+            _ => err(ErrorKind::Type, "only arrays and strings can be sliced"),
         }
+    }
 
-        Node::Var(ref name, ref init) => {
-            let value = match init {
-                Some(ref expr) => execute_expr(expr, env)?,
-                None => Value::Nothing,
-            };
+    // `base[start:end] = value` replaces that part of an array with the
+    // elements of another.
+    pub fn set_slice(&mut self, base: Value, start: Value, end: Value, value: Value) -> VMResult {
+        let (array, values) = match (&base, &value) {
+            (Value::Array(array), Value::Array(values)) => (array, values.borrow().clone()),
+            (Value::Array(_), _) => {
+                return err(ErrorKind::Type, "slice assignment expects an array")
+            }
+            _ => return err(ErrorKind::Type, "only slices of arrays can be assigned to"),
+        };
 
-            env.borrow_mut().define(name.clone(), value.clone());
-            Ok(value)
-        }
+        let length = array.borrow().len();
+        let (start, end) = slice_bounds(start, end, length)?;
+        self.heap.reserve(values.iter().map(value_size).sum())?;
+        array.borrow_mut().splice(start..end, values);
+        Ok(value)
+    }
 
-        Node::Fun(ref name, ref parameters, ref body) => {
-            // ToDo: This probably leaks the environment.
-            env.borrow_mut().define(
-                name.clone(),
-                Value::Function(parameters.clone(), body.clone(), env.clone()),
-            );
-            Ok(Value::Nothing)
-        }
+    fn bounds(
+        &mut self,
+        start: &Option<Box<Expr>>,
+        end: &Option<Box<Expr>>,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Result<(Value, Value), VMError> {
+        let mut bound = |bound: &Option<Box<Expr>>| match bound {
+            Some(bound) => self.execute_expr(bound, env),
+            None => Ok(Value::Nothing),
+        };
+        Ok((bound(start)?, bound(end)?))
+    }
 
-        Node::Return(ref expr) => {
-            let expr = execute_expr(&expr, env)?;
-            Err(VMError::Return(expr))
-        }
+    pub fn print(&mut self, value: &Value) -> Result<(), VMError> {
+        self.write_output(&format!("{}\n", value.printed()))
+    }
 
-        Node::Print(ref expr) => {
-            let expr = execute_expr(&expr, env)?;
-            println!("print: {:?}", expr);
-            Ok(expr)
-        }
+    // Failing to write stops the script, which has no way to recover. The
+    // error keeps the io::Error so the host can tell a broken sink apart from
+    // an interrupt.
+    pub fn write_output(&mut self, text: &str) -> Result<(), VMError> {
+        self.output
+            .write_all(text.as_bytes())
+            .map_err(VMError::Output)
+    }
 
-        Node::While(ref condition, ref block) => {
-            loop {
-                match execute_expr(&condition, env)? {
-                    Value::Boolean(true) => execute_node(&block, env)?,
-                    Value::Boolean(false) => break,
-                    _ => return err("while expects boolean operand"),
-                };
+    fn binary(
+        &mut self,
+        l: &Expr,
+        r: &Expr,
+        env: &Rc<RefCell<Environment>>,
+        op: fn(Value, Value) -> VMResult,
+    ) -> VMResult {
+        let left = self.execute_expr(l, env)?;
+        let right = self.execute_expr(r, env)?;
+        op(left, right)
+    }
+
+    fn call(
+        &mut self,
+        callee: Value,
+        base: Option<Value>,
+        arguments: &[Expr],
+        env: &Rc<RefCell<Environment>>,
+        position: Position,
+    ) -> VMResult {
+        let args = self.arguments(arguments, env)?;
+        self.position = position;
+        let result = self.call_value(callee, base, args);
+        // The rest of the statement runs where the call was made.
+        self.position = position;
+        result
+    }
+
+    fn arguments(
+        &mut self,
+        arguments: &[Expr],
+        env: &Rc<RefCell<Environment>>,
+    ) -> Result<Vec<Value>, VMError> {
+        arguments
+            .iter()
+            .map(|arg| self.execute_expr(arg, env))
+            .collect()
+    }
+
+    // Evaluates the callee and arguments of a call in tail position, for
+    // call_function to make the call once the current one has returned.
+    fn tail_call(&mut self, expr: &Expr, env: &Rc<RefCell<Environment>>) -> VMResult {
+        let (callee, base, arguments, position) = match *expr {
+            Expr::Call(ref c, ref arguments, position) => {
+                (self.execute_expr(c, env)?, None, arguments, position)
             }
+            Expr::MethodCall(ref b, ref k, ref arguments, position) => {
+                let base = self.execute_expr(b, env)?;
+                let key = self.execute_expr(k, env)?;
+                let callee = self.get(base.clone(), key)?;
+                (callee, Some(base), arguments, position)
+            }
+            _ => return Err(VMError::Return(self.execute_expr(expr, env)?)),
+        };
+
+        let args = self.arguments(arguments, env)?;
+        self.position = position;
+        Err(VMError::TailCall(Box::new((callee, base, args))))
+    }
 
-            Ok(Value::Nothing)
+    // The calls the tree-walker is in, innermost first.
+    fn tree_trace(&self) -> Vec<StackFrame> {
+        let mut trace = Vec::with_capacity(self.calls.len() + 1);
+        let mut position = self.position;
+        for (function, call) in self.calls.iter().rev() {
+            trace.push(StackFrame {
+                function: function.clone(),
+                position,
+            });
+            position = *call;
         }
+        trace.push(StackFrame {
+            function: "<script>".to_string(),
+            position,
+        });
+        trace
+    }
+
+    // Runs a script function. Tail calls to other script functions run in
+    // the same frame, one after the other, instead of nesting.
+    fn call_function(&mut self, mut callee: Value, mut args: Vec<Value>) -> VMResult {
+        if self.calls.len() >= self.limits.max_call_depth {
+            return Err(call_stack_exceeded());
+        }
+        self.limits.check_stack()?;
+        self.calls.push((String::new(), self.position));
+
+        let result = loop {
+            let result = match callee {
+                Value::Function(ref name, ref parameters, ref body, ref scope) => {
+                    // ToDo: argument count != paramter count
+                    let mut local = Environment::new_enclosing(scope.clone());
+                    let mut args = args.drain(..);
+                    for _ in parameters {
+                        // Parameters without an argument are nil.
+                        local.push(args.next().unwrap_or(Value::Nothing));
+                    }
+
+                    let name = name.as_deref().unwrap_or("<anonymous>");
+                    let frame = &mut self.calls.last_mut().unwrap().0;
+                    if frame != name {
+                        *frame = name.to_string();
+                    }
+                    self.heap
+                        .environment(local)
+                        .and_then(|local| self.execute_node(body, &local))
+                }
+                _ => unreachable!("only script functions run in frames"),
+            };
+
+            match result {
+                Err(VMError::TailCall(call)) => match *call {
+                    (next @ Value::Function(..), _, next_args) => {
+                        callee = next;
+                        args = next_args;
+                    }
+                    (next, base, next_args) => break self.call_value(next, base, next_args),
+                },
+                Err(VMError::Return(v)) => break Ok(v),
+                Err(e) => break Err(e),
+                Ok(_) => break Ok(Value::Nothing), // Falling off the end returns nil.
+            }
+        };
 
-        Node::If(ref condition, ref then, ref other) => match execute_expr(&condition, env)? {
-            Value::Boolean(true) => execute_node(&then, env),
-            Value::Boolean(false) => execute_node(&other, env),
-            _ => err("if expects boolean operand"),
-        },
+        let result = result.map_err(|e| e.traced(|| self.tree_trace()));
+        let (_, position) = self.calls.pop().unwrap();
+        self.position = position;
+        result
     }
-}
 
-fn execute_expr(expr: &Box<Expr>, env: &Rc<RefCell<Environment>>) -> VMResult {
-    match **expr {
-        Expr::Eq(ref l, ref r) => {
-            let left = execute_expr(&l, env)?;
-            let right = execute_expr(&r, env)?;
-            match (left, right) {
-                (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a == b)),
-                _ => err("Unexpected Eq operands"),
+    pub fn call_value(&mut self, callee: Value, base: Option<Value>, args: Vec<Value>) -> VMResult {
+        match callee {
+            Value::NativeFunction(fun) => fun(self, base, args),
+            Value::Closure(ref closure) => self.call_closure(closure.clone(), None, args),
+            Value::BoundMethod(ref bound) => {
+                self.call_closure(bound.method.clone(), Some(bound.receiver.clone()), args)
             }
-        }
-        Expr::Ne(ref l, ref r) => {
-            let left = execute_expr(&l, env)?;
-            let right = execute_expr(&r, env)?;
-            match (left, right) {
-                (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a != b)),
-                _ => err("Unexpected Ne operands"),
+            Value::Function(..) => self.call_function(callee, args),
+            Value::Class(ref class) => {
+                let instance = Value::Object(self.heap.object(Object::instance(class.clone()))?);
+                // The instance is the result, whatever init returns.
+                if let Some(init) = class.find_method("init") {
+                    self.call_value(bind(&self.heap, init, instance.clone())?, None, args)?;
+                }
+                Ok(instance)
             }
+            _ => err(ErrorKind::Type, "expected function callee"),
         }
-        Expr::Greater(ref l, ref r) => {
-            let left = execute_expr(&l, env)?;
-            let right = execute_expr(&r, env)?;
-            match (left, right) {
-                (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a > b)),
-                _ => err("Unexpected > operands"),
+    }
+
+    // Todo: This is probably going to require a different ownership story
+    pub fn execute_node(&mut self, node: &Node, env: &Rc<RefCell<Environment>>) -> VMResult {
+        self.limits.step()?;
+        match *node {
+            Node::Statements(ref statements) => {
+                let mut last = Value::Nothing;
+                for node in statements {
+                    last = self.execute_node(node, env)?;
+                }
+                Ok(last)
             }
-        }
-        Expr::GreaterEqual(ref l, ref r) => {
-            let left = execute_expr(&l, env)?;
-            let right = execute_expr(&r, env)?;
-            match (left, right) {
-                (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a >= b)),
-                _ => err("Unexpected >= operands"),
+
+            Node::ExpressionStatement(ref expr, position) => {
+                self.position = position;
+                self.execute_expr(expr, env)
             }
-        }
-        Expr::Less(ref l, ref r) => {
-            let left = execute_expr(&l, env)?;
-            let right = execute_expr(&r, env)?;
-            match (left, right) {
-                (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a < b)),
-                _ => err("Unexpected < operands"),
+
+            Node::Block(ref statements) => {
+                let block_scope = self
+                    .heap
+                    .environment(Environment::new_enclosing(env.clone()))?;
+                let mut last = Value::Nothing;
+                for node in statements {
+                    last = self.execute_node(node, &block_scope)?;
+                }
+                Ok(last)
             }
-        }
-        Expr::LessEqual(ref l, ref r) => {
-            let left = execute_expr(&l, env)?;
-            let right = execute_expr(&r, env)?;
-            match (left, right) {
-                (Value::Number(a), Value::Number(b)) => Ok(Value::Boolean(a <= b)),
-                _ => err("Unexpected <= operands"),
+
+            Node::Var(ref name, ref init, position) => {
+                self.position = position;
+                let value = match init {
+                    Some(ref expr) => self.execute_expr(expr, env)?,
+                    None => Value::Nothing,
+                };
+
+                env.borrow_mut().declare(name, value.clone());
+                Ok(value)
             }
-        }
-        Expr::Plus(ref l, ref r) => {
-            let left = execute_expr(&l, env)?;
-            let right = execute_expr(&r, env)?;
-            match (left, right) {
-                (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
-                _ => err("Unexpected Plus operands"),
+
+            Node::Fun(ref name, ref parameters, ref body, _) => {
+                // The function and the environment refer to each other, the
+                // collector frees them once nothing else refers to either.
+                env.borrow_mut().declare(
+                    name,
+                    Value::Function(
+                        Some(name.clone()),
+                        parameters.clone(),
+                        body.clone(),
+                        env.clone(),
+                    ),
+                );
+                Ok(Value::Nothing)
             }
-        }
-        Expr::Minus(ref l, ref r) => {
-            let left = execute_expr(&l, env)?;
-            let right = execute_expr(&r, env)?;
-            match (left, right) {
-                (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a - b)),
-                _ => err("Unexpected Minus operands"),
+
+            Node::Class(ref name, ref superclass, ref methods, position) => {
+                self.position = position;
+                let superclass = match superclass {
+                    Some(ref expr) => match self.execute_expr(expr, env)? {
+                        Value::Class(ref class) => Some(class.clone()),
+                        _ => return err(ErrorKind::Type, "superclass must be a class"),
+                    },
+                    None => None,
+                };
+
+                // Methods of a subclass close over a scope binding `super`.
+                let scope = match superclass {
+                    Some(ref superclass) => {
+                        let mut scope = Environment::new_enclosing(env.clone());
+                        scope.push(Value::Class(superclass.clone()));
+                        self.heap.environment(scope)?
+                    }
+                    None => env.clone(),
+                };
+
+                let mut class = Class::new(name.clone(), superclass);
+                for (method, parameters, body) in methods {
+                    class.define_method(
+                        method.clone(),
+                        Value::Function(
+                            Some(method.clone()),
+                            parameters.clone(),
+                            Box::new(body.clone()),
+                            scope.clone(),
+                        ),
+                    );
+                }
+
+                let class = self.heap.class(class)?;
+                env.borrow_mut().declare(name, Value::Class(class));
+                Ok(Value::Nothing)
             }
-        }
-        Expr::Multiply(ref l, ref r) => {
-            let left = execute_expr(&l, env)?;
-            let right = execute_expr(&r, env)?;
-            match (left, right) {
-                (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * b)),
-                _ => err("Unexpected Multiply operands"),
+
+            Node::Return(ref expr, position) => {
+                self.position = position;
+                let expr = self.execute_expr(expr, env)?;
+                Err(VMError::Return(expr))
             }
-        }
-        Expr::Number(n) => Ok(Value::Number(n)),
-        Expr::String(ref string) => Ok(Value::String(string.clone())),
-        Expr::Boolean(b) => Ok(Value::Boolean(b)),
-        Expr::Call(ref c, ref arguments) => {
-            let callee = execute_expr(c, env)?;
-            call(callee, None, arguments, env)
-        }
-        Expr::MethodCall(ref b, ref k, ref arguments) => {
-            let base = execute_expr(b, env)?;
-            let key = execute_expr(k, env)?;
 
-            let callee = get(base.clone(), key)?;
-            call(callee, Some(base), arguments, env)
-        }
-        Expr::Array(ref values) => {
-            let vals: Result<Vec<Value>, _> =
-                values.iter().map(|arg| execute_expr(&arg, env)).collect();
+            Node::TailCall(ref expr, position) => {
+                self.position = position;
+                self.tail_call(expr, env)
+            }
 
-            Ok(Value::Array(Rc::new(RefCell::new(vals?))))
-        }
-        Expr::Object(ref fields) => {
-            let mut object = Object::new();
-            for (name, expr) in fields {
-                let value = execute_expr(expr, env)?;
-                object.set(name.clone(), value);
+            Node::Print(ref expr, position) => {
+                self.position = position;
+                let expr = self.execute_expr(expr, env)?;
+                self.print(&expr)?;
+                Ok(expr)
             }
-            Ok(Value::Object(Rc::new(RefCell::new(object))))
-        }
-        Expr::Assign(ref name, ref expr) => {
-            let right = execute_expr(&expr, env)?;
-            env.borrow_mut().set(name.to_string(), right.clone())
-        }
-        Expr::Identifier(ref name) => env.borrow().get(name),
-        Expr::Get(ref b, ref k) => {
-            let base = execute_expr(b, env)?;
-            let key = execute_expr(k, env)?;
 
-            get(base, key)
-        }
-        Expr::Set(ref b, ref k, ref v) => {
-            let base = execute_expr(b, env)?;
-            let key = execute_expr(k, env)?;
-            let value = execute_expr(v, env)?;
-
-            match (base, key) {
-                (Value::Array(ref array), Value::Number(n)) if n >= 0 => {
-                    match array.borrow_mut().get_mut(n as usize) {
-                        Some(elem) => *elem = value.clone(),
-                        _ => return err("array index of range"),
+            Node::While(ref condition, ref block, ref update, position) => {
+                loop {
+                    self.position = position;
+                    if !self.execute_expr(condition, env)?.is_truthy() {
+                        break;
+                    }
+                    match self.execute_node(block, env) {
+                        Err(VMError::Break) => break,
+                        Err(VMError::Continue) | Ok(_) => {}
+                        Err(e) => return Err(e),
+                    }
+
+                    if let Some(ref update) = update {
+                        self.position = position;
+                        self.execute_expr(update, env)?;
                     }
                 }
-                (Value::Object(ref object), Value::String(ref string)) => {
-                    object.borrow_mut().set(string.clone(), value.clone());
+
+                Ok(Value::Nothing)
+            }
+
+            Node::Throw(ref expr, position) => {
+                self.position = position;
+                let value = self.execute_expr(expr, env)?;
+                Err(VMError::Throw(value))
+            }
+
+            Node::Try(ref block, ref catch, ref finally) => {
+                let mut result = self.execute_node(block, env);
+
+                if let Some((_, ref handler)) = catch {
+                    // Only exceptions are caught; return, break and continue pass through.
+                    if let Err(e) = result {
+                        let e = e.traced(|| self.tree_trace());
+                        result = match e.into_exception(&self.heap) {
+                            Ok(exception) => {
+                                let mut scope = Environment::new_enclosing(env.clone());
+                                scope.push(exception);
+                                self.heap
+                                    .environment(scope)
+                                    .and_then(|scope| self.execute_node(handler, &scope))
+                            }
+                            Err(e) => Err(e),
+                        };
+                    }
                 }
-                _ => return err("array only"),
+
+                // finally runs on every way out of the try statement, and
+                // its own errors or control flow take precedence.
+                if let Some(ref finally) = finally {
+                    self.execute_node(finally, env)?;
+                }
+
+                result
             }
 
-            Ok(value)
+            Node::Break => Err(VMError::Break),
+
+            Node::Continue => Err(VMError::Continue),
+
+            Node::If(ref condition, ref then, ref other, position) => {
+                self.position = position;
+                if self.execute_expr(condition, env)?.is_truthy() {
+                    self.execute_node(then, env)
+                } else {
+                    self.execute_node(other, env)
+                }
+            }
         }
     }
+
+    fn execute_expr(&mut self, expr: &Expr, env: &Rc<RefCell<Environment>>) -> VMResult {
+        match *expr {
+            Expr::Eq(ref l, ref r) => self.binary(l, r, env, equal),
+            Expr::Ne(ref l, ref r) => self.binary(l, r, env, not_equal),
+            Expr::Greater(ref l, ref r) => self.binary(l, r, env, greater),
+            Expr::GreaterEqual(ref l, ref r) => self.binary(l, r, env, greater_equal),
+            Expr::Less(ref l, ref r) => self.binary(l, r, env, less),
+            Expr::LessEqual(ref l, ref r) => self.binary(l, r, env, less_equal),
+            Expr::Plus(ref l, ref r) => self.binary(l, r, env, add),
+            Expr::Minus(ref l, ref r) => self.binary(l, r, env, subtract),
+            Expr::Multiply(ref l, ref r) => self.binary(l, r, env, multiply),
+            Expr::And(ref l, ref r) => {
+                let left = self.execute_expr(l, env)?;
+                if left.is_truthy() {
+                    self.execute_expr(r, env)
+                } else {
+                    Ok(left)
+                }
+            }
+            Expr::Or(ref l, ref r) => {
+                let left = self.execute_expr(l, env)?;
+                if left.is_truthy() {
+                    Ok(left)
+                } else {
+                    self.execute_expr(r, env)
+                }
+            }
+            Expr::Not(ref expr) => Ok(Value::Boolean(!self.execute_expr(expr, env)?.is_truthy())),
+            Expr::Number(n) => Ok(Value::Number(n)),
+            Expr::String(ref string) => Ok(Value::String(string.clone())),
+            Expr::Boolean(b) => Ok(Value::Boolean(b)),
+            Expr::Nil => Ok(Value::Nothing),
+            Expr::Call(ref c, ref arguments, position) => {
+                let callee = self.execute_expr(c, env)?;
+                self.call(callee, None, arguments, env, position)
+            }
+            Expr::MethodCall(ref b, ref k, ref arguments, position) => {
+                let base = self.execute_expr(b, env)?;
+                let key = self.execute_expr(k, env)?;
+
+                let callee = self.get(base.clone(), key)?;
+                self.call(callee, Some(base), arguments, env, position)
+            }
+            Expr::Array(ref values) => {
+                let vals: Result<Vec<Value>, _> = values
+                    .iter()
+                    .map(|arg| self.execute_expr(arg, env))
+                    .collect();
+
+                Ok(Value::Array(self.heap.array(vals?)?))
+            }
+            Expr::Object(ref fields) => {
+                let mut object = Object::new();
+                for (name, expr) in fields {
+                    let value = self.execute_expr(expr, env)?;
+                    object.set(name.clone(), value);
+                }
+                Ok(Value::Object(self.heap.object(object)?))
+            }
+            Expr::Assign(ref name, local, ref expr) => {
+                let right = self.execute_expr(expr, env)?;
+                match local {
+                    Some((depth, slot)) => env.borrow_mut().set_at(depth, slot, name, right),
+                    None => self.globals.borrow_mut().set(name.to_string(), right),
+                }
+            }
+            Expr::Identifier(ref name, local) => match local {
+                Some((depth, slot)) => env.borrow().get_at(depth, slot, name),
+                None => self.globals.borrow().get(name),
+            },
+            Expr::This(local) => match local {
+                Some((depth, slot)) => env.borrow().get_at(depth, slot, "this"),
+                None => err(ErrorKind::Reference, "this outside of class"),
+            },
+            Expr::Super(ref name, local) => {
+                let (depth, slot) = match local {
+                    Some(local) => local,
+                    None => return err(ErrorKind::Reference, "super outside of class"),
+                };
+
+                // `this` is bound in the scope right inside the one binding `super`.
+                let superclass = env.borrow().get_at(depth, slot, "super")?;
+                let this = env.borrow().get_at(depth - 1, 0, "this")?;
+
+                bind_super(&self.heap, superclass, this, name)
+            }
+            Expr::Function(ref parameters, ref body) => Ok(Value::Function(
+                None,
+                parameters.clone(),
+                body.clone(),
+                env.clone(),
+            )),
+            Expr::Get(ref b, ref k) => {
+                let base = self.execute_expr(b, env)?;
+                let key = self.execute_expr(k, env)?;
+
+                self.get(base, key)
+            }
+            Expr::Set(ref b, ref k, ref v) => {
+                let base = self.execute_expr(b, env)?;
+                let key = self.execute_expr(k, env)?;
+                let value = self.execute_expr(v, env)?;
+
+                self.set(base, key, value)
+            }
+            Expr::Slice(ref b, ref start, ref end) => {
+                let base = self.execute_expr(b, env)?;
+                let (start, end) = self.bounds(start, end, env)?;
+
+                self.slice(base, start, end)
+            }
+            Expr::SetSlice(ref b, ref start, ref end, ref v) => {
+                let base = self.execute_expr(b, env)?;
+                let (start, end) = self.bounds(start, end, env)?;
+                let value = self.execute_expr(v, env)?;
+
+                self.set_slice(base, start, end, value)
+            }
+        }
+    }
+}
+
+impl Drop for Interpreter {
+    // Functions defined at the top level and the globals refer to each
+    // other. Emptying the globals lets everything the host doesn't hold on
+    // to be freed along with the interpreter.
+    fn drop(&mut self) {
+        self.globals.borrow_mut().clear();
+        self.heap.collect();
+        // There's no one left to report a failure to.
+        let _ = self.output.flush();
+    }
 }
@@ -0,0 +1,428 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use crate::environment::Environment;
+use crate::execute::{ErrorKind, VMError};
+use crate::object::{Class, Object};
+use crate::value::Value;
+use crate::vm::{BoundMethod, Closure, Upvalue};
+
+// Reference counting frees values as soon as nothing refers to them, except
+// for cycles: a function stored in the environment it closes over, an array
+// containing itself. The heap keeps a weak reference to everything that can
+// hold other values, and a collection finds the ones only kept alive by each
+// other. References between tracked values are subtracted from their
+// reference counts; whatever is still referenced from elsewhere (the
+// interpreter, the VM stack, a native holding a value) is a root, and the
+// values the roots can't reach are garbage. Emptying the garbage breaks its
+// cycles, and reference counting frees the rest.
+//
+// The heap also keeps a rough count of the bytes in use, so scripts can be
+// given a memory limit. Allocations add to it, and a collection recounts
+// the values that survived.
+
+// Collect once this many values are tracked, and after a collection once
+// twice as many as survived it are.
+const INITIAL_THRESHOLD: usize = 1024;
+
+// The reference counts and borrow flag around every tracked value.
+const HEADER: usize = 3 * mem::size_of::<usize>();
+
+pub fn address<T>(rc: &Rc<T>) -> *const () {
+    Rc::as_ptr(rc) as *const ()
+}
+
+// The bytes a value takes up where it's stored. Strings own their bytes,
+// everything else is counted where it's allocated.
+pub fn value_size(value: &Value) -> usize {
+    let bytes = match value {
+        Value::String(ref string) => string.len(),
+        _ => 0,
+    };
+    mem::size_of::<Value>() + bytes
+}
+
+// Visits the tracked value that `value` refers to, if any.
+pub fn trace_value(value: &Value, visit: &mut dyn FnMut(*const ())) {
+    match value {
+        Value::Function(_, _, _, ref env) => visit(address(env)),
+        Value::Closure(ref closure) => visit(address(closure)),
+        Value::BoundMethod(ref bound) => visit(address(bound)),
+        Value::Array(ref array) => visit(address(array)),
+        Value::Object(ref object) => visit(address(object)),
+        Value::Class(ref class) => visit(address(class)),
+        _ => {}
+    }
+}
+
+enum Tracked {
+    Environment(Weak<RefCell<Environment>>),
+    Array(Weak<RefCell<Vec<Value>>>),
+    Object(Weak<RefCell<Object>>),
+    Class(Weak<Class>),
+    Closure(Weak<Closure>),
+    BoundMethod(Weak<BoundMethod>),
+    Upvalue(Weak<RefCell<Upvalue>>),
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Live> {
+        Some(match self {
+            Tracked::Environment(ref weak) => Live::Environment(weak.upgrade()?),
+            Tracked::Array(ref weak) => Live::Array(weak.upgrade()?),
+            Tracked::Object(ref weak) => Live::Object(weak.upgrade()?),
+            Tracked::Class(ref weak) => Live::Class(weak.upgrade()?),
+            Tracked::Closure(ref weak) => Live::Closure(weak.upgrade()?),
+            Tracked::BoundMethod(ref weak) => Live::BoundMethod(weak.upgrade()?),
+            Tracked::Upvalue(ref weak) => Live::Upvalue(weak.upgrade()?),
+        })
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Tracked::Environment(_) => "environment",
+            Tracked::Array(_) => "array",
+            Tracked::Object(_) => "object",
+            Tracked::Class(_) => "class",
+            Tracked::Closure(_) => "function",
+            Tracked::BoundMethod(_) => "boundMethod",
+            Tracked::Upvalue(_) => "upvalue",
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        match self {
+            Tracked::Environment(ref weak) => weak.strong_count() > 0,
+            Tracked::Array(ref weak) => weak.strong_count() > 0,
+            Tracked::Object(ref weak) => weak.strong_count() > 0,
+            Tracked::Class(ref weak) => weak.strong_count() > 0,
+            Tracked::Closure(ref weak) => weak.strong_count() > 0,
+            Tracked::BoundMethod(ref weak) => weak.strong_count() > 0,
+            Tracked::Upvalue(ref weak) => weak.strong_count() > 0,
+        }
+    }
+}
+
+// A tracked value kept alive for the length of a collection.
+enum Live {
+    Environment(Rc<RefCell<Environment>>),
+    Array(Rc<RefCell<Vec<Value>>>),
+    Object(Rc<RefCell<Object>>),
+    Class(Rc<Class>),
+    Closure(Rc<Closure>),
+    BoundMethod(Rc<BoundMethod>),
+    Upvalue(Rc<RefCell<Upvalue>>),
+}
+
+impl Live {
+    fn address(&self) -> *const () {
+        match self {
+            Live::Environment(ref rc) => address(rc),
+            Live::Array(ref rc) => address(rc),
+            Live::Object(ref rc) => address(rc),
+            Live::Class(ref rc) => address(rc),
+            Live::Closure(ref rc) => address(rc),
+            Live::BoundMethod(ref rc) => address(rc),
+            Live::Upvalue(ref rc) => address(rc),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Live::Environment(ref rc) => Rc::strong_count(rc),
+            Live::Array(ref rc) => Rc::strong_count(rc),
+            Live::Object(ref rc) => Rc::strong_count(rc),
+            Live::Class(ref rc) => Rc::strong_count(rc),
+            Live::Closure(ref rc) => Rc::strong_count(rc),
+            Live::BoundMethod(ref rc) => Rc::strong_count(rc),
+            Live::Upvalue(ref rc) => Rc::strong_count(rc),
+        }
+    }
+
+    // Values borrowed for writing are in the middle of changing and count
+    // as empty.
+    fn size(&self) -> usize {
+        let size = match self {
+            Live::Environment(ref env) => env.try_borrow().map_or(0, |env| env.size()),
+            Live::Array(ref array) => array.try_borrow().map_or(0, |array| {
+                mem::size_of::<Vec<Value>>() + array.iter().map(value_size).sum::<usize>()
+            }),
+            Live::Object(ref object) => object.try_borrow().map_or(0, |object| object.size()),
+            Live::Class(ref class) => class.size(),
+            Live::Closure(ref closure) => {
+                mem::size_of::<Closure>() + closure.upvalues.len() * mem::size_of::<usize>()
+            }
+            Live::BoundMethod(_) => mem::size_of::<BoundMethod>(),
+            Live::Upvalue(ref upvalue) => match upvalue.try_borrow() {
+                Ok(ref upvalue) => match **upvalue {
+                    Upvalue::Closed(ref value) => value_size(value),
+                    Upvalue::Open(_) => mem::size_of::<Upvalue>(),
+                },
+                Err(_) => 0,
+            },
+        };
+        HEADER + size
+    }
+
+    // Visits everything the value refers to. Returns false when the value
+    // is borrowed for writing and can't be looked into, which means it's
+    // in use.
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        match self {
+            Live::Environment(ref env) => match env.try_borrow() {
+                Ok(env) => env.trace(visit),
+                Err(_) => return false,
+            },
+            Live::Array(ref array) => match array.try_borrow() {
+                Ok(array) => array.iter().for_each(|value| trace_value(value, visit)),
+                Err(_) => return false,
+            },
+            Live::Object(ref object) => match object.try_borrow() {
+                Ok(object) => object.trace(visit),
+                Err(_) => return false,
+            },
+            Live::Class(ref class) => class.trace(visit),
+            Live::Closure(ref closure) => closure
+                .upvalues
+                .iter()
+                .for_each(|upvalue| visit(address(upvalue))),
+            Live::BoundMethod(ref bound) => {
+                trace_value(&bound.receiver, visit);
+                visit(address(&bound.method));
+            }
+            Live::Upvalue(ref upvalue) => match upvalue.try_borrow() {
+                Ok(upvalue) => {
+                    if let Upvalue::Closed(ref value) = *upvalue {
+                        trace_value(value, visit);
+                    }
+                }
+                Err(_) => return false,
+            },
+        }
+        true
+    }
+
+    // Drops everything the value refers to. Every cycle runs through an
+    // environment, array, object or upvalue, since classes, closures and
+    // bound methods can only refer to values that existed before them.
+    fn clear(&self) {
+        match self {
+            Live::Environment(ref env) => env.borrow_mut().clear(),
+            Live::Array(ref array) => array.borrow_mut().clear(),
+            Live::Object(ref object) => object.borrow_mut().clear(),
+            Live::Upvalue(ref upvalue) => *upvalue.borrow_mut() = Upvalue::Closed(Value::Nothing),
+            Live::Class(_) | Live::Closure(_) | Live::BoundMethod(_) => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HeapStats {
+    // Tracked values that are still alive, in all and by type.
+    pub objects: usize,
+    pub types: Vec<(&'static str, usize)>,
+    pub bytes: usize,
+    pub collections: usize,
+    // Values freed by collections, not counting what reference counting
+    // freed on its own.
+    pub freed: usize,
+}
+
+pub struct Heap {
+    tracked: RefCell<Vec<Tracked>>,
+    threshold: Cell<usize>,
+    bytes: Cell<usize>,
+    limit: Cell<Option<usize>>,
+    collections: Cell<usize>,
+    freed: Cell<usize>,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            tracked: RefCell::new(Vec::new()),
+            threshold: Cell::new(INITIAL_THRESHOLD),
+            bytes: Cell::new(0),
+            limit: Cell::new(None),
+            collections: Cell::new(0),
+            freed: Cell::new(0),
+        }
+    }
+
+    fn track(&self, tracked: Tracked) {
+        let count = {
+            let mut all = self.tracked.borrow_mut();
+            all.push(tracked);
+            all.len()
+        };
+        if count >= self.threshold.get() {
+            self.collect();
+        }
+    }
+
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit.set(limit);
+    }
+
+    // Accounts for `bytes` more memory in use. Going over the limit first
+    // collects garbage, and fails if that doesn't free enough.
+    pub fn reserve(&self, bytes: usize) -> Result<(), VMError> {
+        if let Some(limit) = self.limit.get() {
+            if self.bytes.get() + bytes > limit {
+                self.collect();
+            }
+            if self.bytes.get() + bytes > limit {
+                return Err(VMError::Message(
+                    ErrorKind::Memory,
+                    format!("out of memory, scripts are limited to {} bytes", limit),
+                ));
+            }
+        }
+        self.bytes.set(self.bytes.get() + bytes);
+        Ok(())
+    }
+
+    fn allocate<T>(
+        &self,
+        size: usize,
+        value: T,
+        tracked: fn(Weak<T>) -> Tracked,
+    ) -> Result<Rc<T>, VMError> {
+        self.reserve(HEADER + size)?;
+        let value = Rc::new(value);
+        self.track(tracked(Rc::downgrade(&value)));
+        Ok(value)
+    }
+
+    pub fn environment(&self, env: Environment) -> Result<Rc<RefCell<Environment>>, VMError> {
+        self.allocate(env.size(), RefCell::new(env), Tracked::Environment)
+    }
+
+    pub fn array(&self, values: Vec<Value>) -> Result<Rc<RefCell<Vec<Value>>>, VMError> {
+        let size = mem::size_of::<Vec<Value>>() + values.iter().map(value_size).sum::<usize>();
+        self.allocate(size, RefCell::new(values), Tracked::Array)
+    }
+
+    pub fn object(&self, object: Object) -> Result<Rc<RefCell<Object>>, VMError> {
+        self.allocate(object.size(), RefCell::new(object), Tracked::Object)
+    }
+
+    pub fn class(&self, class: Class) -> Result<Rc<Class>, VMError> {
+        self.allocate(class.size(), class, Tracked::Class)
+    }
+
+    pub fn closure(&self, closure: Closure) -> Result<Rc<Closure>, VMError> {
+        let size = mem::size_of::<Closure>() + closure.upvalues.len() * mem::size_of::<usize>();
+        self.allocate(size, closure, Tracked::Closure)
+    }
+
+    pub fn bound_method(&self, bound: BoundMethod) -> Result<Rc<BoundMethod>, VMError> {
+        self.allocate(mem::size_of::<BoundMethod>(), bound, Tracked::BoundMethod)
+    }
+
+    pub fn upvalue(&self, upvalue: Upvalue) -> Result<Rc<RefCell<Upvalue>>, VMError> {
+        self.allocate(
+            mem::size_of::<Upvalue>(),
+            RefCell::new(upvalue),
+            Tracked::Upvalue,
+        )
+    }
+
+    // Frees the values that are only referred to by cycles of garbage and
+    // returns how many there were.
+    pub fn collect(&self) -> usize {
+        let live: Vec<Live> = self
+            .tracked
+            .borrow()
+            .iter()
+            .filter_map(Tracked::upgrade)
+            .collect();
+        let index: HashMap<*const (), usize> = live
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (value.address(), i))
+            .collect();
+
+        // Count the references each value gets from other tracked values.
+        // Values in use can't be traced and are roots, as is everything
+        // they refer to, since those references aren't counted.
+        let mut internal = vec![0; live.len()];
+        let mut roots = Vec::new();
+        for (i, value) in live.iter().enumerate() {
+            let traced = value.trace(&mut |address| {
+                if let Some(&j) = index.get(&address) {
+                    internal[j] += 1;
+                }
+            });
+            if !traced {
+                roots.push(i);
+            }
+        }
+
+        // One reference is the `live` vector's own.
+        for (i, value) in live.iter().enumerate() {
+            if value.strong_count() - 1 > internal[i] {
+                roots.push(i);
+            }
+        }
+
+        let mut reachable = vec![false; live.len()];
+        while let Some(i) = roots.pop() {
+            if reachable[i] {
+                continue;
+            }
+            reachable[i] = true;
+            live[i].trace(&mut |address| {
+                if let Some(&j) = index.get(&address) {
+                    if !reachable[j] {
+                        roots.push(j);
+                    }
+                }
+            });
+        }
+
+        let mut freed = 0;
+        let mut bytes = 0;
+        for (value, reachable) in live.iter().zip(reachable) {
+            if reachable {
+                bytes += value.size();
+            } else {
+                value.clear();
+                freed += 1;
+            }
+        }
+        drop(live);
+        self.bytes.set(bytes);
+
+        let mut tracked = self.tracked.borrow_mut();
+        tracked.retain(Tracked::is_live);
+        self.threshold.set(INITIAL_THRESHOLD.max(tracked.len() * 2));
+        self.collections.set(self.collections.get() + 1);
+        self.freed.set(self.freed.get() + freed);
+        freed
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let tracked = self.tracked.borrow();
+        let mut types: Vec<(&'static str, usize)> = Vec::new();
+        for tracked in tracked.iter().filter(|tracked| tracked.is_live()) {
+            match types
+                .iter_mut()
+                .find(|(name, _)| *name == tracked.type_name())
+            {
+                Some((_, count)) => *count += 1,
+                None => types.push((tracked.type_name(), 1)),
+            }
+        }
+
+        HeapStats {
+            objects: types.iter().map(|(_, count)| count).sum(),
+            types,
+            bytes: self.bytes.get(),
+            collections: self.collections.get(),
+            freed: self.freed.get(),
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::execute::{call_stack_exceeded, VMError};
+
+const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+// The tree-walker recurses on the Rust stack for every script call, and so
+// does the VM when natives call script functions. That takes about 7KB per
+// call in release builds and 60KB in debug ones, so the call depth limit
+// alone doesn't keep scripts from overflowing the stack. Calls also stop
+// once scripts use this much of it, which leaves room to spare on threads
+// with the usual 2MB.
+const DEFAULT_STACK_LIMIT: usize = 1024 * 1024;
+
+// The deadline and interrupts are only looked at every this many steps,
+// which keeps reading the clock off the hot path.
+const CHECK_INTERVAL: u64 = 1024;
+
+// Stops the script the interpreter is running. It can be sent to other
+// threads, and interrupting is only an atomic store, so it's safe to do
+// from a signal handler.
+#[derive(Clone)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+// Bounds on how long scripts run. A step is a statement for the
+// tree-walker and an instruction for the VM. Once a limit is hit, every
+// later step fails too, so neither catch nor finally can keep a script
+// going.
+pub struct Limits {
+    steps: u64,
+    // The step at which to look at the limits next. Every step before it
+    // only counts.
+    next_check: u64,
+    step_limit: Option<u64>,
+    deadline: Option<Instant>,
+    interrupted: Arc<AtomicBool>,
+    // Why the script was stopped, if it was.
+    stopped: Option<String>,
+    pub max_call_depth: usize,
+    // Where the stack was when the outermost script started running, and
+    // how far past it scripts can go.
+    stack_base: Option<usize>,
+    pub stack_limit: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            steps: 0,
+            next_check: CHECK_INTERVAL,
+            step_limit: None,
+            deadline: None,
+            interrupted: Arc::new(AtomicBool::new(false)),
+            stopped: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            stack_base: None,
+            stack_limit: DEFAULT_STACK_LIMIT,
+        }
+    }
+}
+
+impl Limits {
+    // Counts steps from now on.
+    pub fn set_step_limit(&mut self, steps: u64) {
+        self.steps = 0;
+        self.step_limit = Some(steps);
+        self.next_check = 0;
+    }
+
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+        self.next_check = 0;
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle(self.interrupted.clone())
+    }
+
+    // Called as each script starts. Interrupts only stop the script running
+    // when they arrive, and each script gets the whole step limit. The
+    // deadline stays in effect.
+    pub fn start(&mut self) {
+        self.steps = 0;
+        self.interrupted.store(false, Ordering::Relaxed);
+        self.stopped = None;
+        self.next_check = 0;
+    }
+
+    // Counts the stack scripts use from here, unless a script is already
+    // running further up it. Returns whether it did, and so has to call
+    // leave_stack once the script is done.
+    pub fn enter_stack(&mut self) -> bool {
+        if self.stack_base.is_some() {
+            return false;
+        }
+        self.stack_base = Some(stack_position());
+        true
+    }
+
+    pub fn leave_stack(&mut self) {
+        self.stack_base = None;
+    }
+
+    // Fails once scripts use more of the stack than they're allowed.
+    pub fn check_stack(&self) -> Result<(), VMError> {
+        match self.stack_base {
+            Some(base) if base.abs_diff(stack_position()) > self.stack_limit => {
+                Err(call_stack_exceeded())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[inline]
+    pub fn step(&mut self) -> Result<(), VMError> {
+        self.steps += 1;
+        if self.steps < self.next_check {
+            return Ok(());
+        }
+        self.stop_if_over()
+    }
+
+    #[cold]
+    fn stop_if_over(&mut self) -> Result<(), VMError> {
+        if self.stopped.is_none() {
+            self.stopped = self.check();
+        }
+        match self.stopped {
+            Some(ref reason) => Err(VMError::Interrupted(reason.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn check(&mut self) -> Option<String> {
+        if let Some(limit) = self.step_limit {
+            if self.steps > limit {
+                return Some(format!("step limit of {} reached", limit));
+            }
+        }
+        if self.interrupted.load(Ordering::Relaxed) {
+            return Some("interrupted by the host".to_string());
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Some("deadline passed".to_string());
+            }
+        }
+
+        self.next_check = self.steps + CHECK_INTERVAL;
+        if let Some(limit) = self.step_limit {
+            self.next_check = self.next_check.min(limit + 1);
+        }
+        None
+    }
+}
+
+// Roughly where the stack of the current thread has got to.
+#[inline(always)]
+fn stack_position() -> usize {
+    let marker = 0u8;
+    &marker as *const u8 as usize
+}
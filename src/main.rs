@@ -1,50 +1,221 @@
-use std::cell::RefCell;
 use std::env;
 use std::error::Error;
-use std::fs::File;
-use std::io::prelude::*;
-use std::rc::Rc;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 
+mod builtins;
+mod bytecode;
+mod chunk;
+mod compiler;
+mod disassembler;
 mod environment;
 mod execute;
+mod gc;
+mod limits;
 mod object;
 mod parser;
+mod resolver;
 mod scanner;
 #[cfg(test)]
 mod test;
 mod value;
+mod vm;
 
-use crate::environment::Environment;
-use crate::execute::execute_node;
-use crate::parser::Parser;
+use crate::compiler::compile;
+use crate::disassembler::disassemble;
+use crate::execute::{format_trace, Interpreter, VMError, VMResult};
+use crate::parser::{Node, Parser};
+use crate::resolver::resolve;
 use crate::scanner::scan;
 use crate::value::Value;
 
-fn println(_base: Option<Value>, args: Vec<Value>) -> Value {
-    println!("println: {:?}", args);
-    Value::Nothing
+// Prints its arguments separated by spaces.
+fn println(interpreter: &mut Interpreter, _base: Option<Value>, args: Vec<Value>) -> VMResult {
+    let line: Vec<String> = args.iter().map(Value::printed).collect();
+    interpreter.write_output(&format!("{}\n", line.join(" ")))?;
+    Ok(Value::Nothing)
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let name = env::args().nth(1).ok_or("missing file argument")?;
-    let mut f = File::open(name)?;
+// The value of a `--name=value` argument.
+fn option<'a>(arg: &'a str, name: &str) -> Option<&'a str> {
+    arg.strip_prefix(name)?.strip_prefix('=')
+}
+
+// Ctrl-C stops the script with an error instead of killing the process.
+#[cfg(unix)]
+mod interrupt {
+    use std::sync::OnceLock;
 
-    let mut buffer = String::new();
-    f.read_to_string(&mut buffer)?;
+    use crate::limits::InterruptHandle;
 
-    let tokens = scan(&buffer)?;
-    println!("{:?}", tokens);
+    const SIGINT: i32 = 2;
+
+    static HANDLE: OnceLock<InterruptHandle> = OnceLock::new();
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+
+    extern "C" fn on_signal(_signum: i32) {
+        if let Some(handle) = HANDLE.get() {
+            handle.interrupt();
+        }
+    }
+
+    pub fn on_ctrl_c(handle: InterruptHandle) {
+        if HANDLE.set(handle).is_ok() {
+            // Safe since the handler only does an atomic store.
+            unsafe {
+                signal(SIGINT, on_signal);
+            }
+        }
+    }
+}
+
+// Scans, parses and resolves a script, printing what each step produced.
+// With `dump`, prints the tokens and the tree they parse to.
+fn parse_source(source: &str, dump: bool) -> Result<Node, Box<dyn Error>> {
+    let tokens = scan(source)?;
+    if dump {
+        println!("{:?}", tokens);
+    }
 
     let mut parser = Parser::new(tokens);
-    let node = parser.parse()?;
-    println!("{:?}", node);
+    let mut node = parser.parse()?;
+    if dump {
+        println!("{:?}", node);
+    }
+
+    for warning in resolve(&mut node)? {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(node)
+}
 
-    let env = Rc::new(RefCell::new(Environment::new()));
-    env.borrow_mut()
-        .define("println".to_string(), Value::NativeFunction(println));
-    match execute_node(&Box::new(node), &env) {
+// Deep recursion on the tree-walker needs a deep stack, see limits.rs.
+// Only the part of it that's used gets allocated. Scripts can use all but
+// the last megabyte of it, however high --max-call-depth is set, so the
+// size doesn't depend on it.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+const STACK_LIMIT: usize = STACK_SIZE - 1024 * 1024;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let runner = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(|| run().map_err(|e| e.to_string()))
+        .map_err(|e| {
+            format!(
+                "couldn't start a thread with {}MB of stack to run the script on: {}",
+                STACK_SIZE / (1024 * 1024),
+                e
+            )
+        })?;
+    match runner.join() {
+        Ok(result) => Ok(result?),
+        Err(_) => Err("the interpreter panicked".into()),
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    // `--vm` runs the script on the bytecode VM instead of the tree-walker,
+    // `--disassemble` prints the bytecode instead of running it, and
+    // `--dump-ast` prints the tokens and syntax tree before running it.
+    // `compile script.lox` writes the bytecode to script.loxc, which runs
+    // on the VM like a script. `--memory-limit=BYTES` stops scripts from
+    // allocating more than about that much, `--step-limit=STEPS` and
+    // `--time-limit=MILLISECONDS` from running longer than that, and
+    // `--max-call-depth=CALLS` from recursing deeper.
+    let mut use_vm = false;
+    let mut memory_limit = None;
+    let mut step_limit = None;
+    let mut time_limit = None;
+    let mut max_call_depth = None;
+    let mut show_bytecode = false;
+    let mut dump_ast = false;
+    let mut compile_only = false;
+    let mut name = None;
+    for (i, arg) in env::args().skip(1).enumerate() {
+        match arg.as_str() {
+            "--vm" => use_vm = true,
+            "--disassemble" => show_bytecode = true,
+            "--dump-ast" => dump_ast = true,
+            "compile" if i == 0 => compile_only = true,
+            _ => {
+                if let Some(bytes) = option(&arg, "--memory-limit") {
+                    memory_limit = Some(bytes.parse::<usize>()?);
+                } else if let Some(steps) = option(&arg, "--step-limit") {
+                    step_limit = Some(steps.parse::<u64>()?);
+                } else if let Some(millis) = option(&arg, "--time-limit") {
+                    time_limit = Some(Duration::from_millis(millis.parse()?));
+                } else if let Some(calls) = option(&arg, "--max-call-depth") {
+                    max_call_depth = Some(calls.parse::<usize>()?);
+                } else {
+                    name = Some(arg);
+                }
+            }
+        }
+    }
+    let name = name.ok_or("missing file argument")?;
+    let bytes = fs::read(&name)?;
+
+    // Compiled files skip straight to the VM.
+    let (node, script) = if bytecode::is_compiled(&bytes) {
+        (None, Some(bytecode::read(&bytes)?))
+    } else {
+        let node = parse_source(&String::from_utf8(bytes)?, dump_ast)?;
+        let script = if use_vm || show_bytecode || compile_only {
+            Some(compile(&node)?)
+        } else {
+            None
+        };
+        (Some(node), script)
+    };
+
+    if compile_only {
+        let script = script.ok_or("nothing to compile")?;
+        let output = Path::new(&name).with_extension("loxc");
+        fs::write(&output, bytecode::write(&script))?;
+        println!("wrote {}", output.display());
+        return Ok(());
+    }
+
+    if show_bytecode {
+        if let Some(ref script) = script {
+            print!("{}", disassemble(script, true));
+        }
+        return Ok(());
+    }
+
+    let mut interpreter = Interpreter::new();
+    interpreter.set_stack_limit(STACK_LIMIT);
+    if let Some(bytes) = memory_limit {
+        interpreter.set_memory_limit(bytes);
+    }
+    if let Some(steps) = step_limit {
+        interpreter.set_step_limit(steps);
+    }
+    if let Some(calls) = max_call_depth {
+        interpreter.set_max_call_depth(calls);
+    }
+    if let Some(duration) = time_limit {
+        interpreter.set_deadline(Instant::now() + duration);
+    }
+    #[cfg(unix)]
+    interrupt::on_ctrl_c(interpreter.interrupt_handle());
+    interpreter.define_global("println", Value::NativeFunction(println));
+    let result = match (script, node) {
+        (Some(script), _) => interpreter.run_script(script),
+        (None, Some(node)) => interpreter.run(&node),
+        (None, None) => unreachable!("a script is either compiled or parsed"),
+    };
+    match result {
         Ok(v) => println!("ok: {}", v),
-        Err(e) => println!("error: {:?}", e),
+        Err(VMError::Traced(e, trace)) => {
+            println!("error: {}{}", e, format_trace(&trace, Some(&name)))
+        }
+        Err(e) => println!("error: {}", e),
     }
 
     // and more! See the other methods for more details.
@@ -1,21 +1,116 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
 
-use crate::execute::{VMError, VMResult};
+use crate::execute::{ErrorKind, VMError, VMResult};
+use crate::gc::{address, trace_value, value_size};
 use crate::value::Value;
 
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    superclass: Option<Rc<Class>>,
+    methods: HashMap<String, Value>,
+}
+
+impl Class {
+    pub fn new(name: String, superclass: Option<Rc<Class>>) -> Self {
+        Class {
+            name,
+            superclass,
+            methods: HashMap::new(),
+        }
+    }
+
+    pub fn define_method(&mut self, name: String, method: Value) {
+        self.methods.insert(name, method);
+    }
+
+    pub fn find_method(&self, name: &str) -> Option<Value> {
+        match self.methods.get(name) {
+            Some(method) => Some(method.clone()),
+            None => self.superclass.as_ref()?.find_method(name),
+        }
+    }
+
+    pub fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Some(ref superclass) = self.superclass {
+            visit(address(superclass));
+        }
+        self.methods
+            .values()
+            .for_each(|method| trace_value(method, visit));
+    }
+
+    pub fn size(&self) -> usize {
+        let methods: usize = self
+            .methods
+            .iter()
+            .map(|(name, method)| name.len() + value_size(method))
+            .sum();
+        mem::size_of::<Class>() + self.name.len() + methods
+    }
+}
+
 #[derive(Debug)]
 pub struct Object {
+    // Field names in insertion order.
+    keys: Vec<String>,
     fields: HashMap<String, Value>,
+    class: Option<Rc<Class>>,
+    prototype: Option<Rc<RefCell<Object>>>,
 }
 
 impl Object {
     pub fn new() -> Self {
+        Object::with_prototype(None)
+    }
+
+    pub fn instance(class: Rc<Class>) -> Self {
+        Object {
+            class: Some(class),
+            ..Object::new()
+        }
+    }
+
+    pub fn with_prototype(prototype: Option<Rc<RefCell<Object>>>) -> Self {
         Object {
+            keys: Vec::new(),
             fields: HashMap::new(),
+            class: None,
+            prototype,
         }
     }
 
+    pub fn class(&self) -> Option<Rc<Class>> {
+        self.class.clone()
+    }
+
+    pub fn prototype(&self) -> Option<Rc<RefCell<Object>>> {
+        self.prototype.clone()
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.fields.contains_key(name)
+    }
+
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    // The fields in insertion order.
+    pub fn fields(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.keys.iter().map(move |key| (key, &self.fields[key]))
+    }
+
     pub fn set(&mut self, name: String, value: Value) {
+        if let Some(field) = self.fields.get_mut(&name) {
+            *field = value;
+            return;
+        }
+
+        self.keys.push(name.clone());
         self.fields.insert(name, value);
     }
 
@@ -24,6 +119,47 @@ impl Object {
             return Ok(val.clone());
         }
 
-        Err(VMError::Message(format!("no property named '{}'", name)))
+        if let Some(ref prototype) = self.prototype {
+            return prototype.borrow().get(name);
+        }
+
+        Err(VMError::Message(
+            ErrorKind::Reference,
+            format!("no property named '{}'", name),
+        ))
+    }
+
+    pub fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Some(ref class) = self.class {
+            visit(address(class));
+        }
+        if let Some(ref prototype) = self.prototype {
+            visit(address(prototype));
+        }
+        self.fields
+            .values()
+            .for_each(|value| trace_value(value, visit));
+    }
+
+    // Field names are stored twice, in `keys` and in `fields`.
+    pub fn size(&self) -> usize {
+        let fields: usize = self
+            .fields
+            .iter()
+            .map(|(name, value)| 2 * name.len() + value_size(value))
+            .sum();
+        mem::size_of::<Object>() + fields
+    }
+
+    pub fn clear(&mut self) {
+        *self = Object::new();
+    }
+
+    // Moves the values the object holds, its prototype among them, into
+    // `values`, leaving it empty.
+    pub fn take_values(&mut self, values: &mut Vec<Value>) {
+        self.keys.clear();
+        values.extend(self.fields.drain().map(|(_, value)| value));
+        values.extend(self.prototype.take().map(Value::Object));
     }
 }
@@ -1,25 +1,49 @@
-use std::collections::HashMap;
-
-use crate::scanner::Token;
+use crate::scanner::{Position, Token};
 
 pub struct Parser {
-    tokens: Vec<Token>,
+    tokens: Vec<(Token, Position)>,
     index: usize,
+    loop_depth: usize,
+    // Try statements around the code being parsed, in the current function.
+    try_depth: usize,
+    in_function: bool,
+    // For each enclosing class, whether it has a superclass.
+    classes: Vec<bool>,
 }
 
 #[derive(Debug, Clone)]
+// Statements that run code of their own end with the position they start at.
 pub enum Node {
-    Var(String, Option<Box<Expr>>),
-    Print(Box<Expr>),
-    Fun(String, Vec<String>, Box<Node>),
-    Return(Box<Expr>),
-    While(Box<Expr>, Box<Node>),
-    If(Box<Expr>, Box<Node>, Box<Node>),
-    ExpressionStatement(Box<Expr>),
-    Statements(Vec<Box<Node>>),
-    Block(Vec<Box<Node>>),
+    Var(String, Option<Box<Expr>>, Position),
+    Print(Box<Expr>, Position),
+    Fun(String, Vec<String>, Box<Node>, Position),
+    // Methods are (name, parameters, body), like Fun.
+    Class(
+        String,
+        Option<Box<Expr>>,
+        Vec<(String, Vec<String>, Node)>,
+        Position,
+    ),
+    Return(Box<Expr>, Position),
+    // Returning a call outside of any try statement. The callee runs in
+    // place of the function returning, so the call stack doesn't grow.
+    TailCall(Box<Expr>, Position),
+    // The optional expression is the update clause of a desugared for loop.
+    While(Box<Expr>, Box<Node>, Option<Box<Expr>>, Position),
+    Break,
+    Continue,
+    Throw(Box<Expr>, Position),
+    // try block, optional catch (binding, block) and optional finally block.
+    Try(Box<Node>, Option<(String, Box<Node>)>, Option<Box<Node>>),
+    If(Box<Expr>, Box<Node>, Box<Node>, Position),
+    ExpressionStatement(Box<Expr>, Position),
+    Statements(Vec<Node>),
+    Block(Vec<Node>),
 }
 
+// How many scopes up a local variable is declared, and its slot in that scope.
+pub type Local = (usize, usize);
+
 #[derive(Debug, Clone)]
 pub enum Expr {
     Eq(Box<Expr>, Box<Expr>),
@@ -31,28 +55,52 @@ pub enum Expr {
     Plus(Box<Expr>, Box<Expr>),
     Minus(Box<Expr>, Box<Expr>),
     Multiply(Box<Expr>, Box<Expr>),
-    Call(Box<Expr>, Vec<Box<Expr>>),
-    MethodCall(Box<Expr>, Box<Expr>, Vec<Box<Expr>>),
-    Array(Vec<Box<Expr>>),
-    Object(HashMap<String, Box<Expr>>),
-    Identifier(String),
-    Assign(String, Box<Expr>),
+    // `and` and `or` evaluate to the operand that decided them, and only
+    // evaluate the right one if the left one didn't.
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    // Calls end with the position of the callee, or of the method name.
+    Call(Box<Expr>, Vec<Expr>, Position),
+    MethodCall(Box<Expr>, Box<Expr>, Vec<Expr>, Position),
+    Array(Vec<Expr>),
+    // Fields in source order, so initializers run left to right.
+    Object(Vec<(String, Expr)>),
+    // The resolver fills in where a local variable lives; None means a global.
+    Identifier(String, Option<Local>),
+    This(Option<Local>),
+    // The method name, and where the resolver found `super`.
+    Super(String, Option<Local>),
+    Assign(String, Option<Local>, Box<Expr>),
+    Function(Vec<String>, Box<Node>),
     Get(Box<Expr>, Box<Expr>),
     Set(Box<Expr>, Box<Expr>, Box<Expr>),
+    // `base[start:end]`, where either bound can be left out.
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>),
+    // `base[start:end] = value`, replacing part of an array.
+    SetSlice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>, Box<Expr>),
     Number(i32),
     String(String),
     Boolean(bool),
+    Nil,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Parser {
-        return Parser { tokens, index: 0 };
+    pub fn new(tokens: Vec<(Token, Position)>) -> Parser {
+        Parser {
+            tokens,
+            index: 0,
+            loop_depth: 0,
+            try_depth: 0,
+            in_function: false,
+            classes: Vec::new(),
+        }
     }
 
     pub fn parse(&mut self) -> Result<Node, String> {
         let mut statements = Vec::new();
         loop {
-            statements.push(Box::new(self.declaration()?));
+            statements.push(self.declaration()?);
 
             if self.current().is_none() {
                 break;
@@ -62,24 +110,42 @@ impl Parser {
     }
 
     fn advance(&mut self) -> Option<&Token> {
-        let token = self.tokens.get(self.index);
+        let token = self.tokens.get(self.index).map(|(token, _)| token);
         self.index += 1;
         token
     }
 
     fn current(&mut self) -> Option<&Token> {
-        self.tokens.get(self.index)
+        self.peek(0)
+    }
+
+    fn peek(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.index + offset).map(|(token, _)| token)
+    }
+
+    // Where the current token starts, or the last one at the end of input.
+    fn position(&self) -> Position {
+        match self.tokens.get(self.index).or_else(|| self.tokens.last()) {
+            Some((_, position)) => *position,
+            None => Position::default(),
+        }
     }
 
     fn declaration(&mut self) -> Result<Node, String> {
         match self.current() {
             Some(Token::Var) => self.var_declaration(),
-            Some(Token::Fun) => self.fun_declaration(),
+            Some(Token::Class) => self.class_declaration(),
+            // Without a name this is an anonymous function expression.
+            Some(Token::Fun) => match self.peek(1) {
+                Some(Token::Identifier(_)) => self.fun_declaration(),
+                _ => self.statement(),
+            },
             _ => self.statement(),
         }
     }
 
     fn var_declaration(&mut self) -> Result<Node, String> {
+        let position = self.position();
         self.advance();
 
         let name = match self.advance() {
@@ -101,10 +167,11 @@ impl Parser {
             _ => return Err("Expected semicolon after var declaration".to_string()),
         }
 
-        Ok(Node::Var(name, init))
+        Ok(Node::Var(name, init, position))
     }
 
     fn fun_declaration(&mut self) -> Result<Node, String> {
+        let position = self.position();
         self.advance();
 
         let name = match self.advance() {
@@ -113,6 +180,66 @@ impl Parser {
         }
         .clone();
 
+        let parameters = self.parameters()?;
+        let block = self.function_body()?;
+        Ok(Node::Fun(name, parameters, Box::new(block), position))
+    }
+
+    fn class_declaration(&mut self) -> Result<Node, String> {
+        let position = self.position();
+        self.advance();
+
+        let name = match self.advance() {
+            Some(Token::Identifier(name)) => name,
+            _ => return Err("expected class name".to_string()),
+        }
+        .clone();
+
+        let superclass = match self.current() {
+            Some(Token::Less) => {
+                self.advance();
+
+                match self.advance() {
+                    // Superclasses are fixed when a class is created, so
+                    // inheriting from itself is the only possible cycle.
+                    Some(Token::Identifier(superclass)) if *superclass == name => {
+                        return Err(format!("class '{}' can't inherit from itself", name))
+                    }
+                    Some(Token::Identifier(superclass)) => {
+                        Some(Box::new(Expr::Identifier(superclass.clone(), None)))
+                    }
+                    _ => return Err("expected superclass name after <".to_string()),
+                }
+            }
+            _ => None,
+        };
+
+        match self.advance() {
+            Some(Token::OpenBrace) => {}
+            _ => return Err("expected open brace { after class name".to_string()),
+        }
+
+        self.classes.push(superclass.is_some());
+        let mut methods = Vec::new();
+        loop {
+            let method = match self.advance() {
+                Some(Token::CloseBrace) => break,
+                Some(Token::Identifier(method)) => method,
+                None => return Err("missing closing brace } after class body".to_string()),
+                _ => return Err("expected method name".to_string()),
+            }
+            .clone();
+
+            let parameters = self.parameters()?;
+            let body = self.function_body()?;
+            methods.push((method, parameters, body));
+        }
+        self.classes.pop();
+
+        Ok(Node::Class(name, superclass, methods, position))
+    }
+
+    fn parameters(&mut self) -> Result<Vec<String>, String> {
         match self.advance() {
             Some(Token::OpenParen) => {}
             _ => return Err("expected open parens (".to_string()),
@@ -141,8 +268,30 @@ impl Parser {
             _ => return Err("expected close parens )".to_string()),
         }
 
-        let block = self.block()?;
-        Ok(Node::Fun(name, parameters, Box::new(block)))
+        Ok(parameters)
+    }
+
+    fn function_body(&mut self) -> Result<Node, String> {
+        // A loop around the function doesn't make break/continue valid inside
+        // it, and a try statement doesn't keep its returns from being tail calls.
+        let loop_depth = self.loop_depth;
+        let try_depth = self.try_depth;
+        let in_function = self.in_function;
+        self.loop_depth = 0;
+        self.try_depth = 0;
+        self.in_function = true;
+        let block = self.block();
+        self.loop_depth = loop_depth;
+        self.try_depth = try_depth;
+        self.in_function = in_function;
+        block
+    }
+
+    fn loop_body(&mut self) -> Result<Node, String> {
+        self.loop_depth += 1;
+        let body = self.statement();
+        self.loop_depth -= 1;
+        body
     }
 
     fn statement(&mut self) -> Result<Node, String> {
@@ -151,6 +300,10 @@ impl Parser {
             Some(Token::Return) => self.return_statement(),
             Some(Token::While) => self.while_statement(),
             Some(Token::For) => self.for_statement(),
+            Some(Token::Break) => self.break_statement(),
+            Some(Token::Continue) => self.continue_statement(),
+            Some(Token::Throw) => self.throw_statement(),
+            Some(Token::Try) => self.try_statement(),
             Some(Token::If) => self.if_statement(),
             Some(Token::OpenBrace) => self.block(),
             _ => self.expression_statement(),
@@ -158,6 +311,7 @@ impl Parser {
     }
 
     fn print_statement(&mut self) -> Result<Node, String> {
+        let position = self.position();
         self.advance();
 
         let expr = self.expression()?;
@@ -165,21 +319,44 @@ impl Parser {
             Some(Token::Semicolon) => {}
             _ => return Err("Expected semicolon after print".to_string()),
         }
-        Ok(Node::Print(Box::new(expr)))
+        Ok(Node::Print(Box::new(expr), position))
     }
 
     fn return_statement(&mut self) -> Result<Node, String> {
+        let position = self.position();
         self.advance();
 
-        let expr = self.expression()?;
+        if !self.in_function {
+            return Err("return outside of function".to_string());
+        }
+
+        // A bare `return;` returns nil.
+        let expr = match self.current() {
+            Some(Token::Semicolon) => Expr::Nil,
+            _ => self.expression()?,
+        };
         match self.advance() {
             Some(Token::Semicolon) => {}
             _ => return Err("Expected semicolon after return".to_string()),
         }
-        Ok(Node::Return(Box::new(expr)))
+
+        // Handlers and finally blocks have to run after the call returns.
+        if self.try_depth > 0 {
+            return Ok(Node::Return(Box::new(expr), position));
+        }
+        Ok(Parser::return_node(expr, position))
+    }
+
+    // Returns of calls are tail calls.
+    fn return_node(expr: Expr, position: Position) -> Node {
+        match expr {
+            Expr::Call(..) | Expr::MethodCall(..) => Node::TailCall(Box::new(expr), position),
+            _ => Node::Return(Box::new(expr), position),
+        }
     }
 
     fn while_statement(&mut self) -> Result<Node, String> {
+        let position = self.position();
         self.advance();
 
         match self.advance() {
@@ -194,11 +371,45 @@ impl Parser {
             _ => return Err("expected close parens ) after condition".to_string()),
         }
 
-        let body = self.statement()?;
-        Ok(Node::While(Box::new(condition), Box::new(body)))
+        let body = self.loop_body()?;
+        Ok(Node::While(
+            Box::new(condition),
+            Box::new(body),
+            None,
+            position,
+        ))
+    }
+
+    fn break_statement(&mut self) -> Result<Node, String> {
+        self.advance();
+
+        if self.loop_depth == 0 {
+            return Err("break outside of loop".to_string());
+        }
+
+        match self.advance() {
+            Some(Token::Semicolon) => {}
+            _ => return Err("Expected semicolon after break".to_string()),
+        }
+        Ok(Node::Break)
+    }
+
+    fn continue_statement(&mut self) -> Result<Node, String> {
+        self.advance();
+
+        if self.loop_depth == 0 {
+            return Err("continue outside of loop".to_string());
+        }
+
+        match self.advance() {
+            Some(Token::Semicolon) => {}
+            _ => return Err("Expected semicolon after continue".to_string()),
+        }
+        Ok(Node::Continue)
     }
 
     fn for_statement(&mut self) -> Result<Node, String> {
+        let position = self.position();
         self.advance();
 
         match self.advance() {
@@ -235,26 +446,89 @@ impl Parser {
             _ => return Err("expected ) after for".to_string()),
         }
 
-        let mut body = self.statement()?;
+        let body = self.loop_body()?;
 
-        // Desugaring
+        // Desugaring. The update stays part of the loop so that `continue` still runs it.
 
-        if let Some(update) = update {
-            body = Node::Block(vec![
-                Box::new(body),
-                Box::new(Node::ExpressionStatement(Box::new(update))),
-            ]);
-        }
-
-        let while_loop = Node::While(Box::new(condition), Box::new(body));
+        let while_loop = Node::While(
+            Box::new(condition),
+            Box::new(body),
+            update.map(Box::new),
+            position,
+        );
 
         Ok(match init {
-            Some(init) => Node::Block(vec![Box::new(init), Box::new(while_loop)]),
+            Some(init) => Node::Block(vec![init, while_loop]),
             _ => while_loop,
         })
     }
 
+    fn throw_statement(&mut self) -> Result<Node, String> {
+        let position = self.position();
+        self.advance();
+
+        let expr = self.expression()?;
+        match self.advance() {
+            Some(Token::Semicolon) => {}
+            _ => return Err("Expected semicolon after throw".to_string()),
+        }
+        Ok(Node::Throw(Box::new(expr), position))
+    }
+
+    fn try_statement(&mut self) -> Result<Node, String> {
+        self.try_depth += 1;
+        let result = self.try_clauses();
+        self.try_depth -= 1;
+        result
+    }
+
+    fn try_clauses(&mut self) -> Result<Node, String> {
+        self.advance();
+
+        let block = self.block()?;
+
+        let catch = match self.current() {
+            Some(Token::Catch) => {
+                self.advance();
+
+                match self.advance() {
+                    Some(Token::OpenParen) => {}
+                    _ => return Err("expected open parens ( after catch".to_string()),
+                }
+
+                let name = match self.advance() {
+                    Some(Token::Identifier(name)) => name,
+                    _ => return Err("expected catch variable name".to_string()),
+                }
+                .clone();
+
+                match self.advance() {
+                    Some(Token::CloseParen) => {}
+                    _ => return Err("expected close parens ) after catch variable".to_string()),
+                }
+
+                Some((name, Box::new(self.block()?)))
+            }
+            _ => None,
+        };
+
+        let finally = match self.current() {
+            Some(Token::Finally) => {
+                self.advance();
+                Some(Box::new(self.block()?))
+            }
+            _ => None,
+        };
+
+        if catch.is_none() && finally.is_none() {
+            return Err("expected catch or finally after try block".to_string());
+        }
+
+        Ok(Node::Try(Box::new(block), catch, finally))
+    }
+
     fn if_statement(&mut self) -> Result<Node, String> {
+        let position = self.position();
         self.advance();
 
         match self.advance() {
@@ -281,6 +555,7 @@ impl Parser {
             Box::new(condition),
             Box::new(then),
             Box::new(other),
+            position,
         ))
     }
 
@@ -301,18 +576,19 @@ impl Parser {
                 None => return Err("missing closing brace }".to_string()),
             }
 
-            statements.push(Box::new(self.declaration()?));
+            statements.push(self.declaration()?);
         }
         Ok(Node::Block(statements))
     }
 
     fn expression_statement(&mut self) -> Result<Node, String> {
+        let position = self.position();
         let expr = self.expression()?;
         match self.advance() {
             Some(Token::Semicolon) => {}
             _ => return Err("Expected semicolon after expression".to_string()),
         }
-        Ok(Node::ExpressionStatement(Box::new(expr)))
+        Ok(Node::ExpressionStatement(Box::new(expr), position))
     }
 
     fn expression(&mut self) -> Result<Expr, String> {
@@ -320,7 +596,7 @@ impl Parser {
     }
 
     fn assignment(&mut self) -> Result<Expr, String> {
-        let left = self.equality()?;
+        let left = self.logical_or()?;
 
         match self.current() {
             Some(Token::Assign) => {
@@ -328,8 +604,11 @@ impl Parser {
 
                 let right = self.assignment()?;
                 match left {
-                    Expr::Identifier(name) => Ok(Expr::Assign(name.clone(), Box::new(right))),
+                    Expr::Identifier(name, local) => Ok(Expr::Assign(name, local, Box::new(right))),
                     Expr::Get(base, key) => Ok(Expr::Set(base, key, Box::new(right))),
+                    Expr::Slice(base, start, end) => {
+                        Ok(Expr::SetSlice(base, start, end, Box::new(right)))
+                    }
                     _ => Err("Unexpected left hand side of assignment".to_string()),
                 }
             }
@@ -337,6 +616,30 @@ impl Parser {
         }
     }
 
+    fn logical_or(&mut self) -> Result<Expr, String> {
+        let mut left = self.logical_and()?;
+
+        while let Some(Token::Or) = self.current() {
+            self.advance();
+
+            let right = self.logical_and()?;
+            left = Expr::Or(Box::new(left), Box::new(right))
+        }
+        Ok(left)
+    }
+
+    fn logical_and(&mut self) -> Result<Expr, String> {
+        let mut left = self.equality()?;
+
+        while let Some(Token::And) = self.current() {
+            self.advance();
+
+            let right = self.equality()?;
+            left = Expr::And(Box::new(left), Box::new(right))
+        }
+        Ok(left)
+    }
+
     fn equality(&mut self) -> Result<Expr, String> {
         let mut left = self.comparison()?;
 
@@ -439,31 +742,57 @@ impl Parser {
                 let expr = self.unary()?;
                 Ok(Expr::Minus(Box::new(Expr::Number(0)), Box::new(expr)))
             }
+            Some(Token::Bang) => {
+                self.advance();
+
+                let expr = self.unary()?;
+                Ok(Expr::Not(Box::new(expr)))
+            }
             _ => self.call(),
         }
     }
 
+    // An index like `base[i]`, or a slice like `base[start:end]`.
+    fn subscript(&mut self, base: Expr) -> Result<Expr, String> {
+        self.advance();
+
+        let start = match self.current() {
+            Some(Token::Colon) => None,
+            _ => Some(Box::new(self.expression()?)),
+        };
+
+        let expr = match (self.current(), start) {
+            (Some(Token::Colon), start) => {
+                self.advance();
+
+                let end = match self.current() {
+                    Some(Token::CloseBracket) => None,
+                    _ => Some(Box::new(self.expression()?)),
+                };
+                Expr::Slice(Box::new(base), start, end)
+            }
+            (_, Some(key)) => Expr::Get(Box::new(base), key),
+            (_, None) => unreachable!("a subscript without a colon has a key"),
+        };
+
+        match self.advance() {
+            Some(Token::CloseBracket) => Ok(expr),
+            _ => Err("expecting ] after index".to_string()),
+        }
+    }
+
     fn call(&mut self) -> Result<Expr, String> {
+        let mut position = self.position();
         let mut expr = self.primary()?;
 
         loop {
             match self.current() {
-                Some(Token::OpenParen) => expr = self.finish_call(expr)?,
-                Some(Token::OpenBracket) => {
-                    self.advance();
-
-                    let key = self.expression()?;
-
-                    match self.advance() {
-                        Some(Token::CloseBracket) => {
-                            expr = Expr::Get(Box::new(expr), Box::new(key))
-                        }
-                        _ => return Err("expecting ] after index".to_string()),
-                    }
-                }
+                Some(Token::OpenParen) => expr = self.finish_call(expr, position)?,
+                Some(Token::OpenBracket) => expr = self.subscript(expr)?,
                 Some(Token::Dot) => {
                     self.advance();
 
+                    position = self.position();
                     match self.advance() {
                         Some(Token::Identifier(name)) => {
                             expr = Expr::Get(Box::new(expr), Box::new(Expr::String(name.clone())))
@@ -476,11 +805,10 @@ impl Parser {
         }
     }
 
-    fn expression_list(&mut self) -> Result<Vec<Box<Expr>>, String> {
+    fn expression_list(&mut self) -> Result<Vec<Expr>, String> {
         let mut list = Vec::new();
         loop {
-            let expr = self.expression()?;
-            list.push(Box::new(expr));
+            list.push(self.expression()?);
 
             match self.current() {
                 Some(Token::Comma) => self.advance(),
@@ -490,7 +818,7 @@ impl Parser {
         Ok(list)
     }
 
-    fn finish_call(&mut self, expr: Expr) -> Result<Expr, String> {
+    fn finish_call(&mut self, expr: Expr, position: Position) -> Result<Expr, String> {
         self.advance(); // (
 
         let arguments = match self.current() {
@@ -500,26 +828,110 @@ impl Parser {
 
         match self.advance() {
             Some(Token::CloseParen) => Ok(match expr {
-                Expr::Get(expr, key) => Expr::MethodCall(expr, key, arguments),
-                expr @ _ => Expr::Call(Box::new(expr), arguments),
+                Expr::Get(expr, key) => Expr::MethodCall(expr, key, arguments, position),
+                expr => Expr::Call(Box::new(expr), arguments, position),
             }),
             _ => Err("expecting ) after calle".to_string()),
         }
     }
 
     fn primary(&mut self) -> Result<Expr, String> {
+        if self.is_arrow_function() {
+            return self.arrow_function();
+        }
+
         match self.advance() {
-            Some(Token::Identifier(name)) => Ok(Expr::Identifier(name.clone())),
+            Some(Token::Identifier(name)) => Ok(Expr::Identifier(name.clone(), None)),
             Some(Token::Number(n)) => Ok(Expr::Number(*n)),
             Some(Token::String(string)) => Ok(Expr::String(string.clone())),
             Some(Token::True) => Ok(Expr::Boolean(true)),
             Some(Token::False) => Ok(Expr::Boolean(false)),
+            Some(Token::Nil) => Ok(Expr::Nil),
+            Some(Token::This) => {
+                if self.classes.is_empty() {
+                    return Err("this outside of class".to_string());
+                }
+                Ok(Expr::This(None))
+            }
+            Some(Token::Super) => self.super_expression(),
             Some(Token::OpenBracket) => self.array(),
             Some(Token::OpenBrace) => self.object(),
-            t @ _ => Err(format!("Unexpected {:?}", t)),
+            Some(Token::OpenParen) => self.grouping(),
+            Some(Token::Fun) => self.function_expression(),
+            t => Err(format!("Unexpected {:?}", t)),
         }
     }
 
+    fn super_expression(&mut self) -> Result<Expr, String> {
+        match self.classes.last() {
+            Some(true) => {}
+            Some(false) => return Err("super in class without superclass".to_string()),
+            None => return Err("super outside of class".to_string()),
+        }
+
+        match self.advance() {
+            Some(Token::Dot) => {}
+            _ => return Err("expecting . after super".to_string()),
+        }
+
+        match self.advance() {
+            Some(Token::Identifier(name)) => Ok(Expr::Super(name.clone(), None)),
+            _ => Err("expecting method name after super.".to_string()),
+        }
+    }
+
+    fn grouping(&mut self) -> Result<Expr, String> {
+        let expr = self.expression()?;
+
+        match self.advance() {
+            Some(Token::CloseParen) => Ok(expr),
+            _ => Err("expecting ) after expression".to_string()),
+        }
+    }
+
+    fn function_expression(&mut self) -> Result<Expr, String> {
+        let parameters = self.parameters()?;
+        let block = self.function_body()?;
+        Ok(Expr::Function(parameters, Box::new(block)))
+    }
+
+    // Looks ahead for `(a, b) =>` to tell an arrow function apart from a grouping.
+    fn is_arrow_function(&self) -> bool {
+        match self.peek(0) {
+            Some(Token::OpenParen) => {}
+            _ => return false,
+        }
+
+        let mut offset = 1;
+        loop {
+            match self.peek(offset) {
+                Some(Token::Identifier(_)) | Some(Token::Comma) => offset += 1,
+                Some(Token::CloseParen) => {
+                    return matches!(self.peek(offset + 1), Some(Token::Arrow))
+                }
+                _ => return false,
+            }
+        }
+    }
+
+    fn arrow_function(&mut self) -> Result<Expr, String> {
+        let parameters = self.parameters()?;
+
+        match self.advance() {
+            Some(Token::Arrow) => {}
+            _ => return Err("expecting => after parameters".to_string()),
+        }
+
+        let body = match self.current() {
+            Some(Token::OpenBrace) => self.function_body()?,
+            _ => {
+                let position = self.position();
+                Parser::return_node(self.expression()?, position)
+            }
+        };
+        Ok(Expr::Function(parameters, Box::new(body)))
+    }
+
     fn array(&mut self) -> Result<Expr, String> {
         let values = match self.current() {
             Some(Token::CloseBracket) => Vec::new(),
@@ -534,9 +946,9 @@ impl Parser {
 
     fn object(&mut self) -> Result<Expr, String> {
         let fields = match self.current() {
-            Some(Token::CloseBrace) => HashMap::new(),
+            Some(Token::CloseBrace) => Vec::new(),
             _ => {
-                let mut fields = HashMap::new();
+                let mut fields = Vec::new();
                 loop {
                     let name = match self.advance() {
                         Some(Token::Identifier(name)) => name,
@@ -551,7 +963,7 @@ impl Parser {
                     };
 
                     let expr = self.expression()?;
-                    fields.insert(name, Box::new(expr));
+                    fields.push((name, expr));
 
                     match self.current() {
                         Some(Token::Comma) => self.advance(),
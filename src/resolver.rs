@@ -0,0 +1,310 @@
+use crate::parser::{Expr, Local, Node};
+
+struct Variable {
+    name: String,
+    defined: bool,
+    used: bool,
+    // Parameters and implicit bindings like `this` aren't reported when unused.
+    report_unused: bool,
+    function_depth: usize,
+}
+
+// Works out which scope and slot each local variable reference belongs to,
+// mirroring the environments execute.rs creates at runtime. Slots are numbered
+// in declaration order. The top level isn't a scope: anything not found in a
+// local scope is a global.
+struct Resolver {
+    scopes: Vec<Vec<Variable>>,
+    function_depth: usize,
+    warnings: Vec<String>,
+}
+
+// Resolves the tree in place and returns the warnings found along the way.
+pub fn resolve(node: &mut Node) -> Result<Vec<String>, String> {
+    let mut resolver = Resolver {
+        scopes: Vec::new(),
+        function_depth: 0,
+        warnings: Vec::new(),
+    };
+    resolver.resolve_node(node)?;
+    Ok(resolver.warnings)
+}
+
+impl Resolver {
+    fn begin_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    fn end_scope(&mut self) {
+        if let Some(scope) = self.scopes.pop() {
+            for variable in scope {
+                if variable.report_unused && !variable.used {
+                    self.warnings
+                        .push(format!("unused local variable '{}'", variable.name));
+                }
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str, report_unused: bool) -> Result<(), String> {
+        let function_depth = self.function_depth;
+        let scope = match self.scopes.last_mut() {
+            Some(scope) => scope,
+            None => return Ok(()),
+        };
+
+        if scope.iter().any(|variable| variable.name == name) {
+            return Err(format!(
+                "duplicate declaration of '{}' in the same scope",
+                name
+            ));
+        }
+
+        scope.push(Variable {
+            name: name.to_string(),
+            defined: false,
+            used: false,
+            report_unused,
+            function_depth,
+        });
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            if let Some(variable) = scope.iter_mut().find(|variable| variable.name == name) {
+                variable.defined = true;
+            }
+        }
+    }
+
+    // Declares and defines a binding the script can't misuse, like a parameter.
+    fn bind(&mut self, name: &str) -> Result<(), String> {
+        self.declare(name, false)?;
+        self.define(name);
+        Ok(())
+    }
+
+    fn resolve_local(&mut self, name: &str, read: bool) -> Result<Option<Local>, String> {
+        let function_depth = self.function_depth;
+        for (depth, scope) in self.scopes.iter_mut().rev().enumerate() {
+            if let Some(slot) = scope.iter().position(|variable| variable.name == name) {
+                let variable = &mut scope[slot];
+                // Referring to it from a function created in the initializer is
+                // fine, the function can only run once the variable is defined.
+                if read && !variable.defined && variable.function_depth == function_depth {
+                    return Err(format!("use of variable '{}' in its own initializer", name));
+                }
+
+                if read {
+                    variable.used = true;
+                }
+                return Ok(Some((depth, slot)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn resolve_function(&mut self, parameters: &[String], body: &mut Node) -> Result<(), String> {
+        self.function_depth += 1;
+        self.begin_scope();
+        for parameter in parameters {
+            self.bind(parameter)?;
+        }
+        self.resolve_node(body)?;
+        self.end_scope();
+        self.function_depth -= 1;
+        Ok(())
+    }
+
+    fn resolve_node(&mut self, node: &mut Node) -> Result<(), String> {
+        match *node {
+            Node::Statements(ref mut statements) => {
+                for node in statements {
+                    self.resolve_node(node)?;
+                }
+            }
+
+            Node::Block(ref mut statements) => {
+                self.begin_scope();
+                for node in statements {
+                    self.resolve_node(node)?;
+                }
+                self.end_scope();
+            }
+
+            Node::Var(ref name, ref mut init, _) => {
+                self.declare(name, true)?;
+                if let Some(ref mut expr) = init {
+                    self.resolve_expr(expr)?;
+                }
+                self.define(name);
+            }
+
+            Node::Fun(ref name, ref parameters, ref mut body, _) => {
+                // Defined up front so the function can call itself.
+                self.declare(name, true)?;
+                self.define(name);
+                self.resolve_function(parameters, body)?;
+            }
+
+            Node::Class(ref name, ref mut superclass, ref mut methods, _) => {
+                self.declare(name, true)?;
+                self.define(name);
+
+                if let Some(ref mut superclass) = superclass {
+                    self.resolve_expr(superclass)?;
+                    self.begin_scope();
+                    self.bind("super")?;
+                }
+
+                // Methods run in a scope binding `this`, see execute::bind.
+                self.begin_scope();
+                self.bind("this")?;
+                for (_, parameters, body) in methods.iter_mut() {
+                    self.resolve_function(parameters, body)?;
+                }
+                self.end_scope();
+
+                if superclass.is_some() {
+                    self.end_scope();
+                }
+            }
+
+            Node::Try(ref mut block, ref mut catch, ref mut finally) => {
+                self.resolve_node(block)?;
+
+                if let Some((ref name, ref mut handler)) = catch {
+                    self.begin_scope();
+                    self.bind(name)?;
+                    self.resolve_node(handler)?;
+                    self.end_scope();
+                }
+
+                if let Some(ref mut finally) = finally {
+                    self.resolve_node(finally)?;
+                }
+            }
+
+            Node::While(ref mut condition, ref mut body, ref mut update, _) => {
+                self.resolve_expr(condition)?;
+                self.resolve_node(body)?;
+                if let Some(ref mut update) = update {
+                    self.resolve_expr(update)?;
+                }
+            }
+
+            Node::If(ref mut condition, ref mut then, ref mut other, _) => {
+                self.resolve_expr(condition)?;
+                self.resolve_node(then)?;
+                self.resolve_node(other)?;
+            }
+
+            Node::Print(ref mut expr, _)
+            | Node::Return(ref mut expr, _)
+            | Node::TailCall(ref mut expr, _)
+            | Node::Throw(ref mut expr, _)
+            | Node::ExpressionStatement(ref mut expr, _) => self.resolve_expr(expr)?,
+
+            Node::Break | Node::Continue => {}
+        }
+
+        Ok(())
+    }
+
+    fn resolve_expr(&mut self, expr: &mut Expr) -> Result<(), String> {
+        match *expr {
+            Expr::Identifier(ref name, ref mut local) => {
+                *local = self.resolve_local(name, true)?;
+            }
+
+            Expr::Assign(ref name, ref mut local, ref mut value) => {
+                self.resolve_expr(value)?;
+                *local = self.resolve_local(name, false)?;
+            }
+
+            Expr::This(ref mut local) => {
+                *local = self.resolve_local("this", true)?;
+            }
+
+            Expr::Super(_, ref mut local) => {
+                *local = self.resolve_local("super", true)?;
+            }
+
+            Expr::Function(ref parameters, ref mut body) => {
+                self.resolve_function(parameters, body)?;
+            }
+
+            Expr::Eq(ref mut l, ref mut r)
+            | Expr::Ne(ref mut l, ref mut r)
+            | Expr::Greater(ref mut l, ref mut r)
+            | Expr::GreaterEqual(ref mut l, ref mut r)
+            | Expr::Less(ref mut l, ref mut r)
+            | Expr::LessEqual(ref mut l, ref mut r)
+            | Expr::Plus(ref mut l, ref mut r)
+            | Expr::Minus(ref mut l, ref mut r)
+            | Expr::Multiply(ref mut l, ref mut r)
+            | Expr::And(ref mut l, ref mut r)
+            | Expr::Or(ref mut l, ref mut r)
+            | Expr::Get(ref mut l, ref mut r) => {
+                self.resolve_expr(l)?;
+                self.resolve_expr(r)?;
+            }
+
+            Expr::Not(ref mut expr) => self.resolve_expr(expr)?,
+
+            Expr::Slice(ref mut base, ref mut start, ref mut end) => {
+                self.resolve_expr(base)?;
+                for bound in start.iter_mut().chain(end.iter_mut()) {
+                    self.resolve_expr(bound)?;
+                }
+            }
+
+            Expr::SetSlice(ref mut base, ref mut start, ref mut end, ref mut value) => {
+                self.resolve_expr(base)?;
+                for bound in start.iter_mut().chain(end.iter_mut()) {
+                    self.resolve_expr(bound)?;
+                }
+                self.resolve_expr(value)?;
+            }
+
+            Expr::Set(ref mut base, ref mut key, ref mut value) => {
+                self.resolve_expr(base)?;
+                self.resolve_expr(key)?;
+                self.resolve_expr(value)?;
+            }
+
+            Expr::Call(ref mut callee, ref mut arguments, _) => {
+                self.resolve_expr(callee)?;
+                for argument in arguments {
+                    self.resolve_expr(argument)?;
+                }
+            }
+
+            Expr::MethodCall(ref mut base, ref mut key, ref mut arguments, _) => {
+                self.resolve_expr(base)?;
+                self.resolve_expr(key)?;
+                for argument in arguments {
+                    self.resolve_expr(argument)?;
+                }
+            }
+
+            Expr::Array(ref mut values) => {
+                for value in values {
+                    self.resolve_expr(value)?;
+                }
+            }
+
+            Expr::Object(ref mut fields) => {
+                for (_, value) in fields {
+                    self.resolve_expr(value)?;
+                }
+            }
+
+            Expr::Number(_) | Expr::String(_) | Expr::Boolean(_) | Expr::Nil => {}
+        }
+
+        Ok(())
+    }
+}
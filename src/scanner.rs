@@ -1,8 +1,13 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
 #[derive(Debug)]
 pub enum Token {
     Assign,
+    Arrow,
     Eq,
     Ne,
+    Bang,
     Greater,
     GreaterEqual,
     Less,
@@ -24,15 +29,56 @@ pub enum Token {
     String(String),
     Identifier(String),
     Var,
+    Class,
+    This,
+    Super,
     Print,
     Fun,
     Return,
     While,
     For,
+    Break,
+    Continue,
+    Throw,
+    Try,
+    Catch,
+    Finally,
     If,
     Else,
+    And,
+    Or,
     True,
     False,
+    Nil,
+}
+
+// Where a token starts in the source, counting lines and columns from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+struct Cursor<'a> {
+    chars: Peekable<Chars<'a>>,
+    position: Position,
+}
+
+impl<'a> Cursor<'a> {
+    fn next(&mut self) -> Option<char> {
+        let ch = self.chars.next()?;
+        if ch == '\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else {
+            self.position.column += 1;
+        }
+        Some(ch)
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
 }
 
 fn single_token(ch: char) -> Option<Token> {
@@ -54,61 +100,69 @@ fn single_token(ch: char) -> Option<Token> {
     }
 }
 
-pub fn scan(source: &str) -> Result<Vec<Token>, String> {
-    let mut iter = source.chars().peekable();
+pub fn scan(source: &str) -> Result<Vec<(Token, Position)>, String> {
+    let mut iter = Cursor {
+        chars: source.chars().peekable(),
+        position: Position { line: 1, column: 1 },
+    };
     let mut tokens = Vec::new();
     loop {
+        let start = iter.position;
         let n = iter.next();
         if n.is_none() {
             break;
         }
 
         if let Some(token) = single_token(n.unwrap()) {
-            tokens.push(token);
+            tokens.push((token, start));
             continue;
         }
 
-        match n.unwrap() {
-            i @ 'a'...'z' | i @ 'A'...'Z' => {
+        let token = match n.unwrap() {
+            i @ 'a'..='z' | i @ 'A'..='Z' => {
                 let mut name = String::new();
                 name.push(i);
 
-                loop {
-                    match iter.peek() {
-                        Some('a'...'z') | Some('A'...'Z') | Some('_') => {
-                            name.push(iter.next().unwrap())
-                        }
-                        _ => break,
-                    };
+                while let Some('a'..='z') | Some('A'..='Z') | Some('_') = iter.peek() {
+                    name.push(iter.next().unwrap());
                 }
 
-                tokens.push(match name.as_str() {
+                match name.as_str() {
                     "var" => Token::Var,
+                    "class" => Token::Class,
+                    "this" => Token::This,
+                    "super" => Token::Super,
                     "print" => Token::Print,
                     "fun" => Token::Fun,
                     "return" => Token::Return,
                     "while" => Token::While,
                     "for" => Token::For,
+                    "break" => Token::Break,
+                    "continue" => Token::Continue,
+                    "throw" => Token::Throw,
+                    "try" => Token::Try,
+                    "catch" => Token::Catch,
+                    "finally" => Token::Finally,
                     "if" => Token::If,
                     "else" => Token::Else,
+                    "and" => Token::And,
+                    "or" => Token::Or,
                     "true" => Token::True,
                     "false" => Token::False,
+                    "nil" => Token::Nil,
                     _ => Token::Identifier(name),
-                });
+                }
             }
 
-            n @ '0'...'9' => {
+            n @ '0'..='9' => {
                 let mut number = String::new();
                 number.push(n);
 
-                loop {
-                    match iter.peek() {
-                        Some('0'...'9') => number.push(iter.next().unwrap()),
-                        _ => break,
-                    };
+                while let Some('0'..='9') = iter.peek() {
+                    number.push(iter.next().unwrap());
                 }
 
-                tokens.push(Token::Number(number.parse().unwrap()));
+                Token::Number(number.parse().unwrap())
             }
 
             '"' => {
@@ -125,50 +179,58 @@ pub fn scan(source: &str) -> Result<Vec<Token>, String> {
                     };
                 }
 
-                tokens.push(Token::String(string));
+                Token::String(string)
             }
 
-            '!' => tokens.push(match iter.peek() {
+            '!' => match iter.peek() {
                 Some('=') => {
                     iter.next();
                     Token::Ne
                 }
-                _ => panic!("nyi"),
-            }),
+                _ => Token::Bang,
+            },
 
-            '=' => tokens.push(match iter.peek() {
+            '=' => match iter.peek() {
                 Some('=') => {
                     iter.next();
                     Token::Eq
                 }
+                Some('>') => {
+                    iter.next();
+                    Token::Arrow
+                }
                 _ => Token::Assign,
-            }),
+            },
 
-            '>' => tokens.push(match iter.peek() {
+            '>' => match iter.peek() {
                 Some('=') => {
                     iter.next();
                     Token::GreaterEqual
                 }
                 _ => Token::Greater,
-            }),
+            },
 
-            '<' => tokens.push(match iter.peek() {
+            '<' => match iter.peek() {
                 Some('=') => {
                     iter.next();
                     Token::LessEqual
                 }
                 _ => Token::Less,
-            }),
+            },
 
             ' ' | '\n' => {
                 // Ignore whitespace
                 continue;
             }
 
-            c @ _ => {
-                return Err(format!("Unexpected token: {}", c));
+            c => {
+                return Err(format!(
+                    "Unexpected token: {} at {}:{}",
+                    c, start.line, start.column
+                ));
             }
-        }
+        };
+        tokens.push((token, start));
     }
 
     Ok(tokens)
@@ -1,7 +1,19 @@
+use std::cell::RefCell;
 use std::error::Error;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::bytecode;
+use crate::chunk::{Chunk, Constant, FunctionProto};
+use crate::compiler::compile;
+use crate::disassembler::disassemble;
+use crate::execute::{Interpreter, VMError};
 use crate::parser::{Node, Parser};
+use crate::resolver::resolve;
 use crate::scanner::scan;
+use crate::value::Value;
 
 fn parse(source: &str) -> Result<Node, Box<dyn Error>> {
     let tokens = scan(source)?;
@@ -9,6 +21,41 @@ fn parse(source: &str) -> Result<Node, Box<dyn Error>> {
     Ok(parser.parse()?)
 }
 
+fn warnings(source: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut node = parse(source)?;
+    Ok(resolve(&mut node)?)
+}
+
+fn eval_tree(source: &str) -> Result<Value, Box<dyn Error>> {
+    let mut node = parse(source)?;
+    resolve(&mut node)?;
+    let mut interpreter = Interpreter::new();
+    Ok(interpreter.run(&node).map_err(|e| e.to_string())?)
+}
+
+fn eval_vm(source: &str) -> Result<Value, Box<dyn Error>> {
+    let mut node = parse(source)?;
+    resolve(&mut node)?;
+    let script = compile(&node)?;
+    let mut interpreter = Interpreter::new();
+    Ok(interpreter.run_script(script).map_err(|e| e.to_string())?)
+}
+
+// Runs the source on both backends, which have to agree.
+fn eval(source: &str) -> Result<Value, Box<dyn Error>> {
+    let tree = eval_tree(source);
+    let vm = eval_vm(source);
+    match (&tree, &vm) {
+        // Tagged, so that `1` and `"1"` don't pass for each other.
+        (Ok(a), Ok(b)) => assert_eq!(a.inspect(2), b.inspect(2), "in {}", source),
+        (Err(_), Err(_)) => {}
+        (Ok(v), Err(e)) | (Err(e), Ok(v)) => {
+            panic!("backends disagree on {}: {} and {}", source, v, e)
+        }
+    }
+    tree
+}
+
 #[test]
 fn simple_addition() {
     assert!(parse("1 + 1;").is_ok());
@@ -41,3 +88,1662 @@ fn for_statement() {
     assert!(parse("for (var a = 1; a < 10; i = i + 1) print i;").is_ok());
     assert!(parse("for (var a = 1; a < 10; i = i + 1) { print i; }").is_ok());
 }
+
+#[test]
+fn function_expression() {
+    assert!(parse("var f = fun (a, b) { return a + b; };").is_ok());
+    assert!(parse("fun () { print 1; }();").is_ok());
+    assert!(parse("var f = (a, b) => a + b;").is_ok());
+    assert!(parse("var f = () => { return 1; };").is_ok());
+    assert!(parse("var f = (1 + 2) * 3;").is_ok());
+
+    assert!(parse("var f = (a, 1) => a;").is_err());
+    assert!(parse("var f = fun (a) a;").is_err());
+
+    assert!(matches!(
+        eval("var add = fun (a, b) { return a + b; }; add(1, 2);"),
+        Ok(Value::Number(3))
+    ));
+    assert!(matches!(
+        eval("var inc = (a) => a + 1; inc(41);"),
+        Ok(Value::Number(42))
+    ));
+    assert!(matches!(eval("((a) => a * 2)(21);"), Ok(Value::Number(42))));
+}
+
+#[test]
+fn closure_captures_environment() {
+    let source = "
+        fun counter() {
+            var count = 0;
+            return () => count = count + 1;
+        }
+        var next = counter();
+        next();
+        next();
+        next();
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(3))));
+
+    let source = "
+        fun adder(n) {
+            return fun (x) { return x + n; };
+        }
+        adder(10)(5);
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(15))));
+}
+
+#[test]
+fn break_continue() {
+    assert!(parse("while (true) { break; }").is_ok());
+    assert!(parse("for (;;) { if (true) continue; }").is_ok());
+
+    assert!(parse("break;").is_err());
+    assert!(parse("if (true) { continue; }").is_err());
+    assert!(parse("while (true) { fun f() { break; } }").is_err());
+    assert!(parse("while (true) { var f = () => { continue; }; }").is_err());
+
+    let source = "
+        var sum = 0;
+        var i = 0;
+        while (true) {
+            i = i + 1;
+            if (i == 10) break;
+            sum = sum + i;
+        }
+        sum;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(45))));
+
+    // continue in a for loop must still run the update expression.
+    let source = "
+        var sum = 0;
+        for (var i = 0; i < 10; i = i + 1) {
+            if (i == 5) continue;
+            sum = sum + i;
+        }
+        sum;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(40))));
+}
+
+#[test]
+fn return_statement() {
+    assert!(parse("fun f() { return; }").is_ok());
+    assert!(parse("fun f() { return 1; }").is_ok());
+    assert!(parse("var f = () => { return; };").is_ok());
+
+    assert!(parse("return 1;").is_err());
+    assert!(parse("return;").is_err());
+    assert!(parse("{ return; }").is_err());
+
+    assert!(matches!(
+        eval("fun f() { return; } f();"),
+        Ok(Value::Nothing)
+    ));
+    assert!(matches!(
+        eval("fun f() { var a = 1; } f();"),
+        Ok(Value::Nothing)
+    ));
+    assert!(matches!(
+        eval("fun f() {} f() == nil;"),
+        Ok(Value::Boolean(true))
+    ));
+    assert!(matches!(
+        eval("var a = nil; a != 1;"),
+        Ok(Value::Boolean(true))
+    ));
+}
+
+#[test]
+fn class_declaration() {
+    assert!(parse("class A {}").is_ok());
+    assert!(parse("class A { init(a) { this.a = a; } get() { return this.a; } }").is_ok());
+    assert!(parse("class A { method() { return () => this; } }").is_ok());
+
+    assert!(parse("class { }").is_err());
+    assert!(parse("class A { var a = 1; }").is_err());
+    assert!(parse("class A { method() {}").is_err());
+    assert!(parse("print this;").is_err());
+    assert!(parse("fun f() { return this; }").is_err());
+}
+
+#[test]
+fn class_instances() {
+    let source = "
+        class Point {
+            init(x, y) {
+                this.x = x;
+                this.y = y;
+            }
+
+            sum() {
+                return this.x + this.y;
+            }
+        }
+        var p = Point(1, 2);
+        p.x = 10;
+        p.sum();
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(12))));
+
+    // A method pulled off an instance stays bound to it.
+    let source = "
+        class Counter {
+            init() { this.count = 0; }
+            increment() { this.count = this.count + 1; return this.count; }
+        }
+        var counter = Counter();
+        var increment = counter.increment;
+        increment();
+        increment();
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(2))));
+
+    // Fields shadow methods.
+    let source = "
+        class A { value() { return 1; } }
+        var a = A();
+        a.value = () => 2;
+        a.value();
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(2))));
+
+    assert!(matches!(
+        eval("class A { init() { return 1; } } A();"),
+        Ok(Value::Object(_))
+    ));
+    assert!(eval("class A {} A().missing();").is_err());
+}
+
+#[test]
+fn class_inheritance() {
+    assert!(parse("class A {} class B < A {}").is_ok());
+    assert!(parse("class A {} class B < A { method() { return super.method(); } }").is_ok());
+
+    assert!(parse("class A < A {}").is_err());
+    assert!(parse("class A < {}").is_err());
+    assert!(parse("class A { method() { return super.method(); } }").is_err());
+    assert!(parse("fun f() { return super.method(); }").is_err());
+    assert!(parse("class A {} class B < A { method() { return super; } }").is_err());
+
+    let source = "
+        class A {
+            name() { return 1; }
+            describe() { return this.name() * 10; }
+        }
+        class B < A {
+            name() { return 2; }
+        }
+        class C < B {
+            describe() { return super.describe() + 1; }
+        }
+        C().describe();
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(21))));
+
+    // super is resolved relative to the class that defines the method,
+    // not the class of the instance.
+    let source = "
+        class A { method() { return 1; } }
+        class B < A { method() { return super.method() + 10; } }
+        class C < B {}
+        C().method();
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(11))));
+
+    let source = "
+        class A { init(a) { this.a = a; } }
+        class B < A { init(a, b) { super.init(a); this.b = b; } }
+        var b = B(1, 2);
+        b.a + b.b;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(3))));
+
+    assert!(eval("var A = 1; class B < A {}").is_err());
+    assert!(
+        eval("class A {} class B < A { method() { return super.missing(); } } B().method();")
+            .is_err()
+    );
+}
+
+#[test]
+fn prototype_chain() {
+    let source = "
+        var base = {greeting: 1, shared: 2};
+        var derived = Object.create(base);
+        derived.greeting = 10;
+        derived.greeting + derived.shared;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(12))));
+
+    // Changes to the prototype are visible through the chain.
+    let source = "
+        var a = {};
+        var b = Object.create(a);
+        var c = Object.create(b);
+        a.value = 5;
+        c.value;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(5))));
+
+    assert!(matches!(
+        eval("var a = {}; Object.getPrototypeOf(Object.create(a)) == nil;"),
+        Ok(Value::Boolean(false))
+    ));
+    assert!(matches!(
+        eval("Object.getPrototypeOf({}) == nil;"),
+        Ok(Value::Boolean(true))
+    ));
+    assert!(eval("Object.create(nil).missing;").is_err());
+    assert!(eval("Object.create(1);").is_err());
+}
+
+#[test]
+fn shared_array_prototype() {
+    // Methods on the shared prototype are visible to every array.
+    let source = "
+        var proto = Object.getPrototypeOf([]);
+        proto.answer = () => 42;
+        [1, 2].answer();
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(42))));
+
+    let source = "
+        var a = [1];
+        a.push(2, 3);
+        a.length;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(3))));
+}
+
+#[test]
+fn object_field_order() {
+    // Field initializers run left to right.
+    let source = "
+        var log = [];
+        fun record(value) {
+            log.push(value);
+            return value;
+        }
+        var object = {c: record(1), a: record(2), b: record(3)};
+        log[0] * 100 + log[1] * 10 + log[2];
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(123))));
+
+    // Keys keep insertion order, and reassignment doesn't move a key.
+    let source = "
+        var object = {zebra: 1, apple: 2};
+        object.mango = 3;
+        object.zebra = 4;
+        Object.keys(object);
+    ";
+    match eval(source) {
+        Ok(Value::Array(ref keys)) => {
+            let keys: Vec<String> = keys.borrow().iter().map(|key| key.to_string()).collect();
+            assert_eq!(keys, vec!["zebra", "apple", "mango"]);
+        }
+        _ => panic!("expected an array of keys"),
+    }
+}
+
+#[test]
+fn try_statement() {
+    assert!(parse("try { throw 1; } catch (e) { print e; }").is_ok());
+    assert!(parse("try { throw 1; } finally { print 2; }").is_ok());
+    assert!(parse("try { throw 1; } catch (e) {} finally {}").is_ok());
+
+    assert!(parse("try { throw 1; }").is_err());
+    assert!(parse("try { } catch { }").is_err());
+    assert!(parse("try { } catch (1) { }").is_err());
+    assert!(parse("throw;").is_err());
+
+    assert!(matches!(
+        eval("var a = 0; try { throw 42; } catch (e) { a = e; } a;"),
+        Ok(Value::Number(42))
+    ));
+    assert!(eval("throw 1;").is_err());
+
+    // Runtime errors become error objects with a kind and message.
+    let source = "
+        var kind;
+        try {
+            missing;
+        } catch (e) {
+            kind = e.kind;
+        }
+        kind;
+    ";
+    match eval(source) {
+        Ok(Value::String(ref kind)) => assert_eq!(kind, "ReferenceError"),
+        _ => panic!("expected error kind"),
+    }
+
+    let source = "
+        var message;
+        try {
+            1 + [];
+        } catch (e) {
+            message = e.message;
+        }
+        message;
+    ";
+    match eval(source) {
+        Ok(Value::String(ref message)) => assert_eq!(message, "Unexpected Plus operands"),
+        _ => panic!("expected error message"),
+    }
+
+    // Exceptions unwind through calls.
+    let source = "
+        fun fail() { throw {code: 7}; }
+        fun middle() { fail(); return 1; }
+        var code = 0;
+        try { middle(); } catch (e) { code = e.code; }
+        code;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(7))));
+
+    // Exceptions thrown in catch propagate.
+    assert!(eval("try { throw 1; } catch (e) { throw 2; }").is_err());
+}
+
+#[test]
+fn finally_always_runs() {
+    let source = "
+        var log = [];
+        fun f() {
+            try {
+                return 1;
+            } finally {
+                log.push(2);
+            }
+        }
+        f() + log[0];
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(3))));
+
+    let source = "
+        var count = 0;
+        while (true) {
+            try {
+                break;
+            } finally {
+                count = count + 1;
+            }
+        }
+        count;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(1))));
+
+    let source = "
+        var count = 0;
+        for (var i = 0; i < 3; i = i + 1) {
+            try {
+                continue;
+            } finally {
+                count = count + 1;
+            }
+        }
+        count;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(3))));
+
+    let source = "
+        var ran = false;
+        try {
+            try {
+                throw 1;
+            } finally {
+                ran = true;
+            }
+        } catch (e) {}
+        ran;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Boolean(true))));
+
+    // A return in finally overrides the pending exception.
+    let source = "
+        fun f() {
+            try {
+                throw 1;
+            } finally {
+                return 2;
+            }
+        }
+        f();
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(2))));
+}
+
+#[test]
+fn resolver_errors() {
+    assert!(warnings("var a = 1; var a = 2;").is_ok());
+    assert!(warnings("var a = a;").is_ok());
+    assert!(warnings("{ var a = 1; { var a = a; } }").is_err());
+    assert!(warnings("{ var a = 1; var a = 2; }").is_err());
+    assert!(warnings("fun f(a, a) {}").is_err());
+    assert!(warnings("{ fun f() {} var f = 1; }").is_err());
+
+    // Recursive local functions only run once the variable is defined.
+    assert!(matches!(
+        eval("{ var f = (n) => n; var fact = fun (n) { if (n == 0) { return 1; } return n * fact(n - 1); }; f(fact(5)); }"),
+        Ok(Value::Number(120))
+    ));
+}
+
+#[test]
+fn resolver_warnings() {
+    let found = warnings("fun f(unused) { var a = 1; var b = 2; return b; }").unwrap();
+    assert_eq!(found, vec!["unused local variable 'a'".to_string()]);
+
+    // Assigning a variable isn't using it.
+    let found = warnings("{ var a; a = 1; }").unwrap();
+    assert_eq!(found, vec!["unused local variable 'a'".to_string()]);
+
+    assert!(warnings("var global = 1;").unwrap().is_empty());
+    assert!(warnings("try {} catch (e) {}").unwrap().is_empty());
+}
+
+#[test]
+fn closures_bind_lexically() {
+    // The closure keeps seeing the global even after the block declares
+    // a variable with the same name.
+    let source = "
+        var a = 1;
+        var result = [];
+        {
+            fun show() {
+                result.push(a);
+            }
+            show();
+            var a = 2;
+            show();
+            result.push(a);
+        }
+        result[0] * 100 + result[1] * 10 + result[2];
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(112))));
+}
+
+#[test]
+fn closures_share_variables() {
+    let source = "
+        fun counter() {
+            var count = 0;
+            var inc = () => count = count + 1;
+            var get = () => count;
+            return [inc, get];
+        }
+        var c = counter();
+        c[0]();
+        c[0]();
+        c[1]();
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(2))));
+
+    // Each iteration gets its own variable, which outlives the loop.
+    let source = "
+        var fs = [];
+        for (var i = 0; i < 5; i = i + 1) {
+            var j = i;
+            if (j == 3) {
+                break;
+            }
+            fs.push(() => j * 10);
+        }
+        fs[0]() + fs[1]() + fs[2]();
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(30))));
+}
+
+#[test]
+fn exceptions_unwind_calls() {
+    let source = "
+        fun inner(n) {
+            if (n == 0) {
+                throw 42;
+            }
+            return inner(n - 1);
+        }
+        var log = [];
+        fun outer() {
+            try {
+                inner(5);
+            } finally {
+                log.push(1);
+            }
+        }
+        var result = 0;
+        try {
+            outer();
+        } catch (e) {
+            result = e + log[0];
+        }
+        result;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(43))));
+
+    let source = "
+        fun f() {
+            var total = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                try {
+                    if (i == 2) {
+                        continue;
+                    }
+                    if (i == 4) {
+                        return total;
+                    }
+                } finally {
+                    total = total + 10;
+                }
+                total = total + i;
+            }
+        }
+        f();
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(44))));
+
+    let source = "
+        var a = 0;
+        try {
+            try {
+                [].missing();
+            } catch (e) {
+                throw e.kind;
+            } finally {
+                a = 1;
+            }
+        } catch (kind) {
+            a = a + 1;
+        }
+        a;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(2))));
+}
+
+#[test]
+fn disassemble_script() {
+    let source = "var a = 1;\nwhile (a < 3) {\n    a = a + 1;\n}\nfun f(x) {\n    return x;\n}\n";
+    let mut node = parse(source).unwrap();
+    resolve(&mut node).unwrap();
+    let listing = disassemble(&compile(&node).unwrap(), true);
+    let lines: Vec<&str> = listing.lines().collect();
+
+    assert_eq!(lines[0], "== <script> ==");
+    assert!(lines.contains(&"0004    | Constant          0 1"));
+    assert!(lines.contains(&"0019    | JumpIfFalse      14 -> 0036"));
+    assert!(lines.contains(&"0022    3 GetGlobal         1 \"a\""));
+    assert!(lines.contains(&"0033    2 Loop             24 -> 0012"));
+    assert!(lines.contains(&"0040    5 Closure           3 <fn f>"));
+    assert!(lines.contains(&"== f =="));
+    assert!(lines.contains(&"0000    6 GetLocal          1"));
+
+    // Function values can be disassembled from scripts, but only compiled ones.
+    let source = "disassemble(fun (a) { return a; });";
+    assert_eq!(
+        printed_on(source, true).unwrap(),
+        "== <anonymous> ==
+0000    1 GetLocal          1
+0002    | Return
+0003    | Nil
+0004    | Return
+"
+    );
+    assert!(printed_on(source, false).is_err());
+}
+
+#[test]
+fn bytecode_file_round_trip() {
+    let source = "
+        class A {
+            init(n) {
+                this.n = n;
+            }
+        }
+        fun make(a) {
+            return () => a.n * 2;
+        }
+        var result = -1;
+        try {
+            throw make(A(21));
+        } catch (f) {
+            result = f();
+        }
+        result;
+    ";
+    let mut node = parse(source).unwrap();
+    resolve(&mut node).unwrap();
+    let script = compile(&node).unwrap();
+    let bytes = bytecode::write(&script);
+
+    let loaded = bytecode::read(&bytes).unwrap();
+    assert_eq!(disassemble(&script, true), disassemble(&loaded, true));
+    let mut interpreter = Interpreter::new();
+    assert!(matches!(
+        interpreter.run_script(loaded),
+        Ok(Value::Number(42))
+    ));
+
+    let mut other_version = bytes.clone();
+    other_version[4] = other_version[4].wrapping_add(1);
+    match bytecode::read(&other_version) {
+        Err(e) => assert!(e.contains("version"), "{}", e),
+        Ok(_) => panic!("loaded a file with another version"),
+    }
+
+    assert!(bytecode::read(&bytes[..bytes.len() - 1]).is_err());
+    assert!(bytecode::read(b"print 1;").is_err());
+}
+
+fn proto(upvalue_count: usize, code: &[u8], constants: Vec<Constant>) -> FunctionProto {
+    FunctionProto {
+        name: None,
+        arity: 0,
+        upvalue_count,
+        chunk: Chunk {
+            code: code.to_vec(),
+            constants,
+            positions: Vec::new(),
+        },
+    }
+}
+
+// Whether a file whose script has this code and constants loads.
+fn loads(code: &[u8], constants: Vec<Constant>) -> bool {
+    bytecode::read(&bytecode::write(&proto(0, code, constants))).is_ok()
+}
+
+#[test]
+fn bytecode_verification() {
+    use crate::chunk::OpCode::{
+        Add, Call, Closure, GetLocal, GetUpvalue, Jump, JumpIfFalse, Nil, Pop, PopHandler,
+        PushHandler, Return, Throw, True,
+    };
+
+    assert!(loads(&[Nil as u8, Return as u8], Vec::new()));
+    // Running off the end of the code.
+    assert!(!loads(&[Nil as u8], Vec::new()));
+    // Jumping to the end of the code, or into the middle of an instruction.
+    assert!(!loads(&[Jump as u8, 0, 0], Vec::new()));
+    let code = [Jump as u8, 0, 1, GetLocal as u8, 0, Return as u8];
+    assert!(!loads(&code, Vec::new()));
+    // Popping more than there is.
+    assert!(!loads(&[Add as u8, Return as u8], Vec::new()));
+    // Reaching an instruction with different numbers of values on the stack.
+    let code = [
+        True as u8,
+        JumpIfFalse as u8,
+        0,
+        1,
+        Nil as u8,
+        Nil as u8,
+        Return as u8,
+    ];
+    assert!(!loads(&code, Vec::new()));
+    // Reading a local that isn't on the stack.
+    assert!(!loads(&[GetLocal as u8, 1, Return as u8], Vec::new()));
+
+    // Capturing a local that isn't on the stack, or an upvalue the script
+    // doesn't have.
+    let function = Rc::new(proto(1, &[GetUpvalue as u8, 0, Return as u8], Vec::new()));
+    let closure = |is_local: u8, index: u8| {
+        let code = [Closure as u8, 0, 0, is_local, index, Return as u8];
+        loads(&code, vec![Constant::Function(function.clone())])
+    };
+    assert!(closure(1, 0));
+    assert!(!closure(1, 2));
+    assert!(!closure(0, 0));
+
+    // Handlers are popped on every path, and never more than were pushed.
+    let code = [
+        PushHandler as u8,
+        0,
+        4,
+        PopHandler as u8,
+        Jump as u8,
+        0,
+        1,
+        Pop as u8,
+        Nil as u8,
+        Return as u8,
+    ];
+    assert!(loads(&code, Vec::new()));
+    assert!(!loads(
+        &[PopHandler as u8, Nil as u8, Return as u8],
+        Vec::new()
+    ));
+
+    // A function returning with its handler still pushed. Even if it gets
+    // to run, the handler goes with its frame rather than catching the
+    // exception from the next function.
+    let leaves_handler = [
+        PushHandler as u8,
+        0,
+        2,
+        Nil as u8,
+        Return as u8,
+        Return as u8,
+    ];
+    assert!(!loads(&leaves_handler, Vec::new()));
+    let script = [
+        Closure as u8,
+        0,
+        0,
+        Call as u8,
+        0,
+        Pop as u8,
+        Closure as u8,
+        0,
+        1,
+        Call as u8,
+        0,
+        Return as u8,
+    ];
+    let constants = vec![
+        Constant::Function(Rc::new(proto(0, &leaves_handler, Vec::new()))),
+        Constant::Function(Rc::new(proto(0, &[Nil as u8, Throw as u8], Vec::new()))),
+    ];
+    let result = Interpreter::new().run_script(Rc::new(proto(0, &script, constants)));
+    assert!(matches!(result, Err(VMError::Traced(..))));
+}
+
+#[test]
+fn garbage_cycles_are_collected() {
+    let source = "
+        fun make() {
+            var a = [];
+            a.push(a);
+            var o = {};
+            o.self = o;
+            fun loop(n) {
+                return loop(n);
+            }
+            return nil;
+        }
+        var before = heapStats().objects;
+        for (var i = 0; i < 100; i = i + 1) {
+            make();
+        }
+        gc();
+        heapStats().objects - before;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(0))));
+
+    // Cycles that are still reachable survive.
+    let source = "
+        fun counter() {
+            var n = 0;
+            fun inc() {
+                n = n + 1;
+                return n;
+            }
+            return inc;
+        }
+        var c = counter();
+        var o = {x: 1};
+        o.self = o;
+        c();
+        gc();
+        c() + o.self.self.x;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(3))));
+
+    // Collections also run on their own as the heap grows.
+    let source = "
+        fun f() {
+            var a = [];
+            a.push(a);
+        }
+        for (var i = 0; i < 5000; i = i + 1) {
+            f();
+        }
+    ";
+    let mut node = parse(source).unwrap();
+    resolve(&mut node).unwrap();
+    let mut interpreter = Interpreter::new();
+    interpreter.run(&node).unwrap();
+    let stats = interpreter.heap_stats();
+    assert!(stats.collections > 0);
+    assert!(stats.objects < 2048, "{} live objects", stats.objects);
+}
+
+#[test]
+fn deeply_nested_values_are_freed() {
+    // Values are freed a level at a time, so however deeply they're nested,
+    // dropping them doesn't overflow the stack: when the script lets go of
+    // them, when a collection breaks a cycle through them, and when the
+    // interpreter goes away.
+    let source = "
+        var a = [];
+        var o = {};
+        for (var i = 0; i < 50000; i = i + 1) {
+            a = [a];
+            o = Object.create({inner: o});
+        }
+        a = nil;
+        o = nil;
+
+        var first = [];
+        a = first;
+        for (var i = 0; i < 50000; i = i + 1) {
+            a = [a];
+        }
+        first.push(a);
+        first = nil;
+        a = nil;
+        gc();
+
+        a = [];
+        for (var i = 0; i < 50000; i = i + 1) {
+            a = [a];
+        }
+        nil;
+    ";
+    assert!(matches!(eval(source), Ok(Value::Nothing)));
+}
+
+#[test]
+fn memory_limit() {
+    fn run_limited(source: &str, vm: bool) -> Result<Value, String> {
+        let mut node = parse(source).unwrap();
+        resolve(&mut node).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_memory_limit(100_000);
+        let result = if vm {
+            interpreter.run_script(compile(&node).unwrap())
+        } else {
+            interpreter.run(&node)
+        };
+        result.map_err(|e| e.to_string())
+    }
+
+    for vm in [false, true] {
+        let source = "
+            var a = [];
+            while (true) {
+                a.push(a.length);
+            }
+        ";
+        match run_limited(source, vm) {
+            Err(e) => assert!(e.starts_with("MemoryError: out of memory"), "{}", e),
+            Ok(v) => panic!("unbounded allocation finished with {}", v),
+        }
+
+        // Garbage doesn't count against the limit, and running out of
+        // memory can be caught once the memory is dropped.
+        let source = "
+            fun fill() {
+                var a = [];
+                a.push(a);
+                for (var i = 0; i < 1000; i = i + 1) {
+                    a.push(i);
+                }
+            }
+            for (var i = 0; i < 100; i = i + 1) {
+                fill();
+            }
+            fun grow() {
+                var a = [];
+                while (true) {
+                    a.push(a);
+                }
+            }
+            var kind = nil;
+            try {
+                grow();
+            } catch (e) {
+                kind = e.kind;
+            }
+            kind;
+        ";
+        assert_eq!(run_limited(source, vm).unwrap().to_string(), "MemoryError");
+    }
+
+    let source = "
+        class A {}
+        var keep = [A(), A(), {}];
+        var types = heapStats().types;
+        types.array * 100 + types.object * 10 + types[\"class\"];
+    ";
+    // The globals' environment holds three more objects: Object and the
+    // prototypes of arrays and strings.
+    let result = eval_tree(source).unwrap();
+    assert!(matches!(result, Value::Number(161)), "{}", result);
+}
+
+#[test]
+fn execution_limits() {
+    fn run(source: &str, vm: bool, setup: impl FnOnce(&mut Interpreter)) -> String {
+        let mut node = parse(source).unwrap();
+        resolve(&mut node).unwrap();
+        let mut interpreter = Interpreter::new();
+        setup(&mut interpreter);
+        let result = if vm {
+            interpreter.run_script(compile(&node).unwrap())
+        } else {
+            interpreter.run(&node)
+        };
+        match result {
+            Ok(v) => v.to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    // Interrupts can't be caught, and finally doesn't run to completion.
+    let source = "
+        var log = [];
+        while (true) {
+            try {
+                while (true) {}
+            } catch (e) {
+                log.push(e);
+            } finally {
+                while (true) {}
+            }
+        }
+    ";
+    for vm in [false, true] {
+        assert_eq!(
+            run(source, vm, |i| i.set_step_limit(10_000)),
+            "execution interrupted: step limit of 10000 reached"
+        );
+        assert_eq!(
+            run(source, vm, |i| i
+                .set_deadline(Instant::now() + Duration::from_millis(20))),
+            "execution interrupted: deadline passed"
+        );
+
+        let interrupted = run(source, vm, |i| {
+            let handle = i.interrupt_handle();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                handle.interrupt();
+            });
+        });
+        assert_eq!(
+            interrupted,
+            "execution interrupted: interrupted by the host"
+        );
+
+        assert_eq!(
+            run("var a = 0; a = a + 1;", vm, |i| i.set_step_limit(100)),
+            "1"
+        );
+
+        // Each run gets the whole step limit, rather than what the runs
+        // before it left.
+        let mut node = parse("var i = 0; while (i < 100) { i = i + 1; }").unwrap();
+        resolve(&mut node).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_step_limit(1500);
+        for _ in 0..8 {
+            let result = if vm {
+                interpreter.run_script(compile(&node).unwrap())
+            } else {
+                interpreter.run(&node)
+            };
+            assert!(result.is_ok(), "{}", result.unwrap_err());
+        }
+    }
+}
+
+#[test]
+fn call_depth_limit() {
+    let source = "
+        fun down(n) {
+            if (n == 0) {
+                return 0;
+            }
+            return down(n - 1) + 1;
+        }
+        fun forever(n) {
+            return 1 + forever(n + 1);
+        }
+        var message = nil;
+        try {
+            forever(0);
+        } catch (e) {
+            message = e.stack;
+        }
+        [message, down(30)];
+    ";
+    // Making 50 calls on the tree-walker takes more stack than the test
+    // thread has, so this runs on a thread with room for them.
+    let runner = thread::Builder::new().stack_size(64 * 1024 * 1024);
+    let runner = runner.spawn(move || {
+        let mut results = Vec::new();
+        for vm in [false, true] {
+            let mut node = parse(source).unwrap();
+            resolve(&mut node).unwrap();
+            let mut interpreter = Interpreter::new();
+            interpreter.set_max_call_depth(50);
+            interpreter.set_stack_limit(32 * 1024 * 1024);
+            let result = if vm {
+                interpreter.run_script(compile(&node).unwrap())
+            } else {
+                interpreter.run(&node)
+            };
+            let result = match result {
+                Ok(Value::Array(ref array)) => array.borrow().clone(),
+                Ok(v) => panic!("expected an array, got {}", v),
+                Err(e) => panic!("{}", e),
+            };
+            assert!(matches!(result[1], Value::Number(30)));
+            results.push(result[0].to_string());
+        }
+        results
+    });
+    let results = runner.unwrap().join().unwrap();
+
+    // Both backends trace the same calls.
+    assert_eq!(results[0], results[1]);
+    let lines: Vec<&str> = results[0].lines().collect();
+    assert_eq!(lines[0], "RangeError: maximum call stack size exceeded");
+    assert_eq!(lines[1], "    at forever (9:24)");
+    assert_eq!(lines[11], "    ... 41 more");
+
+    // The default limits are safe on the test thread's stack, which is far
+    // smaller than what it takes the tree-walker to make 1000 calls. So is
+    // recursing through natives.
+    let source = "
+        fun forever(n) {
+            return 1 + forever(n + 1);
+        }
+        fun through_natives(n) {
+            return [n].map((x) => through_natives(x + 1))[0];
+        }
+        var messages = [];
+        try {
+            forever(0);
+        } catch (e) {
+            messages.push(e.message);
+        }
+        try {
+            through_natives(0);
+        } catch (e) {
+            messages.push(e.message);
+        }
+        print messages;
+    ";
+    assert_eq!(
+        printed(source),
+        "[\"maximum call stack size exceeded\", \"maximum call stack size exceeded\"]\n"
+    );
+}
+
+// Run with `cargo test --release -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_fib() {
+    let source = "
+        fun fib(n) {
+            if (n == 0) {
+                return 0;
+            }
+            if (n == 1) {
+                return 1;
+            }
+
+            return fib(n - 1) + fib(n - 2);
+        }
+
+        fib(25);
+    ";
+
+    let start = Instant::now();
+    assert!(matches!(eval_tree(source), Ok(Value::Number(75025))));
+    println!("fib(25), tree-walker: {:?}", start.elapsed());
+
+    let start = Instant::now();
+    assert!(matches!(eval_vm(source), Ok(Value::Number(75025))));
+    println!("fib(25), vm: {:?}", start.elapsed());
+}
+
+#[test]
+fn stack_traces() {
+    let source = "
+        fun inner(x) {
+            return x + nil;
+        }
+        fun middle(x) {
+            var y = inner(x);
+            return y;
+        }
+        var outer = () => middle(1) + 1;
+        outer();
+    ";
+    let tree = eval_tree(source).unwrap_err().to_string();
+    let vm = eval_vm(source).unwrap_err().to_string();
+    assert_eq!(tree, vm);
+    assert_eq!(
+        tree,
+        "TypeError: Unexpected Plus operands
+    at inner (3:13)
+    at middle (6:21)
+    at <anonymous> (9:27)
+    at <script> (10:9)"
+    );
+
+    // Caught errors carry the trace from where they were raised.
+    let source = "
+        class A {
+            check(n) { if (n == 0) { return this.missing; } return n; }
+        }
+        var stack = nil;
+        try {
+            A().check(0);
+        } catch (e) {
+            stack = e.stack;
+        }
+        stack;
+    ";
+    let stack = eval(source).unwrap().to_string();
+    assert_eq!(
+        stack,
+        "ReferenceError: no property named 'missing'
+    at check (3:38)
+    at <script> (7:17)"
+    );
+}
+
+#[test]
+fn tail_calls() {
+    assert!(matches!(
+        parse("fun f() { return f(); }"),
+        Ok(Node::Statements(ref nodes)) if matches!(nodes[0], Node::Fun(_, _, ref body, _)
+            if matches!(**body, Node::Block(ref body) if matches!(body[0], Node::TailCall(..))))
+    ));
+    // Returns inside a try statement have to come back for the handlers.
+    assert!(matches!(
+        parse("fun f() { try { return f(); } finally {} }"),
+        Ok(Node::Statements(ref nodes)) if matches!(nodes[0], Node::Fun(_, _, ref body, _)
+            if matches!(**body, Node::Block(ref body)
+                if matches!(body[0], Node::Try(ref block, _, _)
+                    if matches!(**block, Node::Block(ref block) if matches!(block[0], Node::Return(..))))))
+    ));
+
+    // Far deeper than the call depth limit.
+    let source = "
+        fun countdown(n) {
+            if (n == 0) {
+                return 0;
+            }
+            return countdown(n - 1);
+        }
+        countdown(1000000);
+    ";
+    assert!(matches!(eval(source), Ok(Value::Number(0))));
+
+    // Mutual recursion, methods and arrow functions.
+    let source = "
+        fun even(n) { if (n == 0) { return true; } return odd(n - 1); }
+        fun odd(n) { if (n == 0) { return false; } return even(n - 1); }
+        class Counter {
+            init() { this.count = 0; }
+            add(n) {
+                if (n == 0) { return this.count; }
+                this.count = this.count + 1;
+                return this.add(n - 1);
+            }
+        }
+        fun down(n) { if (n == 0) { return 0; } return next(n - 1); }
+        var next = (n) => down(n);
+        [even(10001), Counter().add(5000), down(3000)];
+    ";
+    let result = eval(source).unwrap();
+    if let Value::Array(ref array) = result {
+        let array = array.borrow();
+        assert!(matches!(array[0], Value::Boolean(false)));
+        assert!(matches!(array[1], Value::Number(5000)));
+        assert!(matches!(array[2], Value::Number(0)));
+    } else {
+        panic!("expected an array, got {}", result);
+    }
+
+    // Natives and classes called in tail position return their result.
+    let source = "
+        class B {}
+        class A { init(n) { this.n = n; return B(); } }
+        fun make(n) { return A(n); }
+        fun append(a) { return a.push(1); }
+        [make(3).n, append([1, 2])];
+    ";
+    assert_eq!(
+        eval(source).unwrap().inspect(2),
+        eval("[3, nil];").unwrap().inspect(2)
+    );
+}
+
+// Collects what scripts print.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A sink whose reader has gone away.
+struct Closed;
+
+impl Write for Closed {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "reader closed"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// What the source prints when it runs on the VM or on the tree-walker.
+fn printed_on(source: &str, vm: bool) -> Result<String, VMError> {
+    let mut node = parse(source).unwrap();
+    resolve(&mut node).unwrap();
+    let output = Output::default();
+    let mut interpreter = Interpreter::with_output(Box::new(output.clone()));
+    if vm {
+        interpreter.run_script(compile(&node).unwrap())?;
+    } else {
+        interpreter.run(&node)?;
+    }
+    let bytes = output.0.borrow().clone();
+    Ok(String::from_utf8(bytes).unwrap())
+}
+
+fn printed(source: &str) -> String {
+    let mut outputs = Vec::new();
+    for vm in [false, true] {
+        match printed_on(source, vm) {
+            Ok(output) => outputs.push(output),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    assert_eq!(outputs[0], outputs[1], "in {}", source);
+    outputs.remove(0)
+}
+
+#[test]
+fn print_statement() {
+    assert_eq!(printed("print 42; print \"hello\";"), "42\nhello\n");
+    assert_eq!(
+        printed("print [1, \"two\", nil, true, [3]];"),
+        "[1, \"two\", nil, true, [3]]\n"
+    );
+
+    let source = "
+        class Point {
+            init(x, y) { this.x = x; this.y = y; }
+            sum() { return this.x + this.y; }
+        }
+        var p = Point(1, 2);
+        print p;
+        print Point;
+        print p.sum;
+        print {name: \"origin\", at: [0, 0]};
+        print () => 1;
+    ";
+    assert_eq!(
+        printed(source),
+        "Point {x: 1, y: 2}\n<class Point>\n<fn sum>\n{name: \"origin\", at: [0, 0]}\n<fn>\n"
+    );
+
+    // A broken sink stops the script, and try can't swallow it.
+    let mut node = parse("try { print 1; } catch (e) {} print 2;").unwrap();
+    resolve(&mut node).unwrap();
+    for vm in [false, true] {
+        let mut interpreter = Interpreter::with_output(Box::new(Closed));
+        let result = if vm {
+            interpreter.run_script(compile(&node).unwrap())
+        } else {
+            interpreter.run(&node)
+        };
+        match result {
+            Err(VMError::Output(e)) => assert_eq!(e.kind(), io::ErrorKind::BrokenPipe),
+            other => panic!("expected an output error, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn printing_deeply_nested_values() {
+    let source = "
+        var a = [];
+        var o = {};
+        for (var i = 0; i < 50000; i = i + 1) {
+            a = [a];
+            o = {inner: o};
+        }
+        print a;
+        print o;
+        print inspect(a, 1000000);
+    ";
+    let array = format!("{}[...]{}", "[".repeat(100), "]".repeat(100));
+    let object = format!("{}{{...}}{}", "{inner: ".repeat(100), "}".repeat(100));
+    let inspected = format!("{}<array: 1>{}", "<array: [".repeat(100), "]>".repeat(100));
+    assert_eq!(
+        printed(source),
+        format!("{}\n{}\n{}\n", array, object, inspected)
+    );
+}
+
+#[test]
+fn value_formatting() {
+    let source = "
+        var a = [1, \"a\", {x: 2}];
+        a.push(a);
+        var o = {name: \"o\"};
+        o.self = o;
+        o.list = [o, [1, [2, [3]]]];
+        var shared = [1];
+        print a;
+        print o;
+        print [shared, shared];
+    ";
+    assert_eq!(
+        printed(source),
+        "[1, \"a\", {x: 2}, [Circular]]
+{name: \"o\", self: [Circular], list: [[Circular], [1, [2, [3]]]]}
+[[1], [1]]
+"
+    );
+
+    let source = "
+        class Point { init(x) { this.x = x; } }
+        var a = [1, [\"a\", [nil]], Point(2)];
+        print inspect(a, 0);
+        print inspect(a, 1);
+        print inspect(a);
+        print inspect(Point, 5);
+        print inspect(() => 1);
+    ";
+    assert_eq!(
+        printed(source),
+        "<array: 3>
+<array: [<number: 1>, <array: 2>, <Point object>]>
+<array: [<number: 1>, <array: [<string: \"a\">, <array: 1>]>, <Point object: {x: <number: 2>}>]>
+<class: Point>
+<function>
+"
+    );
+    assert!(eval("inspect(1, -1);").is_err());
+}
+
+#[test]
+fn truthiness() {
+    let source = "
+        var values = [nil, false, true, 0, \"\", [], {}];
+        var i = 0;
+        while (i < 7) {
+            if (values[i]) print \"yes\"; else print \"no\";
+            i = i + 1;
+        }
+        var n = 3;
+        var count = 0;
+        for (; n; n = nil) count = count + 1;
+        print count;
+        print !nil;
+        print !0;
+        print !!\"\";
+    ";
+    assert_eq!(
+        printed(source),
+        "no\nno\nyes\nyes\nyes\nyes\nyes\n1\ntrue\nfalse\ntrue\n"
+    );
+}
+
+#[test]
+fn logical_operators() {
+    let source = "
+        var calls = 0;
+        fun count(value) { calls = calls + 1; return value; }
+        print nil or \"default\";
+        print 0 or \"default\";
+        print 1 and 2;
+        print false and count(1);
+        print true or count(1);
+        print calls;
+        print nil and 1 or 2;
+        print 1 or 2 and nil;
+        print 1 == 1 and 2 > 1;
+    ";
+    assert_eq!(
+        printed(source),
+        "default\n0\n2\nfalse\ntrue\n0\n2\n1\ntrue\n"
+    );
+}
+
+#[test]
+fn string_methods() {
+    let source = "
+        var s = \"  héllo wörld 🎉 \";
+        var t = s.trim();
+        print t.length;
+        print t[1];
+        print t[12];
+        print t.substring(6);
+        print t.substring(0, 5);
+        print t.indexOf(\"wö\");
+        print t.indexOf(\"x\");
+        print t.split(\" \");
+        print \"a🎉b\".split(\"\");
+        print \"a🎉b\".chars();
+        print t.toUpperCase();
+        print \"STRASSE\".toLowerCase();
+        print \"ß\".toUpperCase();
+        print \"a-b-c\".replace(\"-\", \"+\");
+        print t.startsWith(\"hé\");
+        print t.endsWith(\"🎉\");
+        print t.endsWith(\"d\");
+    ";
+    assert_eq!(
+        printed(source),
+        "13
+é
+🎉
+wörld 🎉
+héllo
+6
+-1
+[\"héllo\", \"wörld\", \"🎉\"]
+[\"a\", \"🎉\", \"b\"]
+[\"a\", \"🎉\", \"b\"]
+HÉLLO WÖRLD 🎉
+strasse
+SS
+a+b-c
+true
+true
+false
+"
+    );
+
+    assert!(eval("\"abc\"[3];").is_err());
+    assert!(eval("\"abc\"[-4];").is_err());
+    assert!(eval("\"abc\".substring(2, 1);").is_err());
+    assert!(eval("\"abc\".substring(0, 4);").is_err());
+    assert!(eval("\"abc\".substring(\"a\");").is_err());
+    assert!(eval("\"abc\".indexOf(1);").is_err());
+    assert!(eval("\"abc\".replace(\"a\");").is_err());
+    assert!(eval("var trim = \"abc\".trim; trim();").is_err());
+    assert!(eval("\"abc\".missing;").is_err());
+}
+
+#[test]
+fn array_methods() {
+    let source = "
+        var a = [1, 2, 3];
+        print a.pop();
+        print a.shift();
+        print a.unshift(0, 1);
+        print a;
+        a.push(3);
+        print a.slice(1, 3);
+        print a.slice(2);
+        print a.splice(1, 2, \"x\", \"y\", \"z\");
+        print a;
+        print a.concat([4, 5], 6);
+        print a.indexOf(\"y\");
+        print a.indexOf(9);
+        print a.includes(\"z\");
+        print [1, 2, 3].reverse();
+        print [1, \"a\", nil].join(\", \");
+        print [1, 2].join();
+        print [].pop();
+        print a.includes(a) or [a].includes(a);
+    ";
+    assert_eq!(
+        printed(source),
+        "3
+1
+3
+[0, 1, 2]
+[1, 2]
+[2, 3]
+[1, 2]
+[0, \"x\", \"y\", \"z\", 3]
+[0, \"x\", \"y\", \"z\", 3, 4, 5, 6]
+2
+-1
+true
+[3, 2, 1]
+1, a, nil
+1,2
+nil
+true
+"
+    );
+
+    let source = "
+        var a = [3, 1, 4, 1, 5];
+        print a.map((x, i) => x * i);
+        print a.filter((x) => x > 2);
+        print a.reduce((sum, x) => sum + x);
+        print a.reduce((sum, x) => sum + x, 100);
+        var seen = [];
+        a.forEach((x, i, array) => seen.push(array.length - i));
+        print seen;
+        print a.find((x) => x > 3);
+        print a.find((x) => x > 5);
+        print a.some((x) => x == 4);
+        print a.every((x) => x > 0);
+        print a.every((x) => x > 1);
+        print [\"b\", \"c\", \"a\"].sort();
+        print a.sort();
+        print a.sort((x, y) => y - x);
+        var pairs = [[2, \"a\"], [1, \"b\"], [2, \"c\"], [1, \"d\"]];
+        print pairs.sort((x, y) => x[0] - y[0]);
+        var growing = [1, 2];
+        growing.forEach((x) => growing.push(x));
+        print growing;
+    ";
+    assert_eq!(
+        printed(source),
+        "[0, 1, 8, 3, 20]
+[3, 4, 5]
+14
+114
+[5, 4, 3, 2, 1]
+4
+nil
+true
+true
+false
+[\"a\", \"b\", \"c\"]
+[1, 1, 3, 4, 5]
+[5, 4, 3, 1, 1]
+[[1, \"b\"], [1, \"d\"], [2, \"a\"], [2, \"c\"]]
+[1, 2, 1, 2]
+"
+    );
+
+    assert!(eval("[].reduce((a, b) => a);").is_err());
+    assert!(eval("[1].map(1);").is_err());
+    assert!(eval("[1, \"a\"].sort();").is_err());
+    assert!(eval("[2, 1].sort((a, b) => nil);").is_err());
+    assert!(eval("[1].slice(\"a\");").is_err());
+    assert!(eval("[1].splice(0, -1);").is_err());
+    assert!(eval("[1].join(1);").is_err());
+    assert!(eval("var pop = [1].pop; pop();").is_err());
+    assert!(eval("[1].map((x) => missing);").is_err());
+}
+
+#[test]
+fn negative_indices_and_slices() {
+    let source = "
+        var a = [1, 2, 3, 4, 5];
+        print a[-1];
+        a[-2] = 40;
+        print a;
+        print a[1:3];
+        print a[:2];
+        print a[3:];
+        print a[:];
+        print a[-2:];
+        print a[:-3];
+        print a[-100:100];
+        print a[4:1];
+        print a.slice(-2, 100);
+        print a.slice(4, 1);
+        var s = \"héllo 🎉\";
+        print s[-1];
+        print s[1:4];
+        print s[:-2];
+        print s[6:];
+        var copy = a[:];
+        copy[0] = 0;
+        print a[0];
+        a[1:3] = [\"x\"];
+        print a;
+        a[:0] = [\"front\", \"more\"];
+        print a;
+        a[-1:] = [];
+        print a;
+        a[:] = a;
+        print a;
+        print (a[1:2] = [9]);
+    ";
+    assert_eq!(
+        printed(source),
+        "5
+[1, 2, 3, 40, 5]
+[2, 3]
+[1, 2]
+[40, 5]
+[1, 2, 3, 40, 5]
+[40, 5]
+[1, 2]
+[1, 2, 3, 40, 5]
+[]
+[40, 5]
+[]
+🎉
+éll
+héllo
+🎉
+1
+[1, \"x\", 40, 5]
+[\"front\", \"more\", 1, \"x\", 40, 5]
+[\"front\", \"more\", 1, \"x\", 40]
+[\"front\", \"more\", 1, \"x\", 40]
+[9]
+"
+    );
+
+    assert!(parse("a[1:2:3];").is_err());
+    assert!(parse("a[1:2;").is_err());
+    assert!(eval("[1, 2][-3];").is_err());
+    assert!(eval("var a = [1]; a[-2] = 1;").is_err());
+    assert!(eval("[1, 2][\"a\":];").is_err());
+    assert!(eval("1[0:1];").is_err());
+    assert!(eval("var a = [1]; a[0:1] = 2;").is_err());
+    assert!(eval("var s = \"ab\"; s[0:1] = [\"c\"];").is_err());
+}
@@ -1,10 +1,14 @@
 use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fmt;
 use std::rc::Rc;
 
 use crate::environment::Environment;
-use crate::object::Object;
+use crate::execute::{Interpreter, VMResult};
+use crate::gc::address;
+use crate::object::{Class, Object};
 use crate::parser::Node;
+use crate::vm::{BoundMethod, Closure};
 
 #[derive(Debug, Clone)]
 pub enum Value {
@@ -12,29 +16,246 @@ pub enum Value {
     Number(i32),
     String(String),
     Boolean(bool),
-    NativeFunction(fn(Option<Value>, Vec<Value>) -> Value),
-    Function(Vec<String>, Box<Node>, Rc<RefCell<Environment>>),
+    NativeFunction(fn(&mut Interpreter, Option<Value>, Vec<Value>) -> VMResult),
+    // A script function for the tree-walker: its name, unless it's
+    // anonymous, parameters, body and the scope it closes over.
+    Function(
+        Option<String>,
+        Vec<String>,
+        Box<Node>,
+        Rc<RefCell<Environment>>,
+    ),
+    // Functions compiled for the VM.
+    Closure(Rc<Closure>),
+    BoundMethod(Rc<BoundMethod>),
     Array(Rc<RefCell<Vec<Value>>>),
     Object(Rc<RefCell<Object>>),
+    Class(Rc<Class>),
 }
 
+// Display shows values the way print does. `inspect` has the tagged form.
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.printed())
+    }
+}
+
+// Arrays and objects nested deeper than this are written as `[...]` and
+// `{...}`, since writing them takes stack for each level.
+const MAX_DEPTH: usize = 100;
+
+// How to write values out.
+#[derive(Clone, Copy)]
+enum Style {
+    // As print shows them.
+    Printed,
+    // Tagged with their type, with the contents of arrays and objects down
+    // to this many levels.
+    Inspected(usize),
+}
+
+impl Value {
+    // Conditions and logical operators treat nil and false as false, and
+    // every other value, including 0, "" and [], as true.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nothing | Value::Boolean(false))
+    }
+
+    // Whether two values are the same, as `indexOf` and `includes` look for
+    // them: numbers, strings and booleans by value, and arrays, objects,
+    // classes and VM functions by identity. Other functions are never the
+    // same as anything.
+    pub fn same(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nothing, Value::Nothing) => true,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => Rc::ptr_eq(a, b),
+            (Value::Array(a), Value::Array(b)) => Rc::ptr_eq(a, b),
+            (Value::Object(a), Value::Object(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    // What print shows: the value itself, like `42`, `hello` or
+    // `[1, "a", {x: 2}]`.
+    pub fn printed(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, Style::Printed, false, &mut HashSet::new());
+        out
+    }
+
+    // The value tagged with its type, like `<array: [<number: 1>]>`.
+    // Arrays and objects deeper than `depth`, or than MAX_DEPTH, only show
+    // their size.
+    pub fn inspect(&self, depth: usize) -> String {
+        let mut out = String::new();
+        let style = Style::Inspected(depth.min(MAX_DEPTH));
+        self.write(&mut out, style, false, &mut HashSet::new());
+        out
+    }
+
+    // Strings inside arrays and objects are quoted. `parents` holds the
+    // arrays and objects being written around this value, and one that
+    // contains itself is written as `[Circular]` the second time.
+    fn write(
+        &self,
+        out: &mut String,
+        style: Style,
+        nested: bool,
+        parents: &mut HashSet<*const ()>,
+    ) {
+        let inspected = matches!(style, Style::Inspected(_));
+        let tag = |out: &mut String, name: &str, value: &str| {
+            if inspected {
+                out.push_str(&format!("<{}: {}>", name, value));
+            } else {
+                out.push_str(value);
+            }
+        };
+
         match self {
-            Value::Nothing => write!(f, "<nothing>"),
-            Value::Number(n) => write!(f, "<number: {}>", n),
-            Value::String(ref string) => write!(f, "<string: {}>", string),
-            Value::Boolean(b) => write!(f, "<boolean: {}>", b),
-            Value::NativeFunction(_) => write!(f, "<native function>"),
-            Value::Function(_, _, _) => write!(f, "<function>"),
-            Value::Array(ref array) => write!(f, "<array: {}>", array.borrow().len()),
-            Value::Object(_) => write!(f, "<object>"),
+            Value::Nothing if inspected => out.push_str("<nothing>"),
+            Value::Nothing => out.push_str("nil"),
+            Value::Number(n) => tag(out, "number", &n.to_string()),
+            Value::String(ref string) if nested => tag(out, "string", &format!("{:?}", string)),
+            Value::String(ref string) => tag(out, "string", string),
+            Value::Boolean(b) => tag(out, "boolean", &b.to_string()),
+            Value::NativeFunction(_) if inspected => out.push_str("<native function>"),
+            Value::NativeFunction(_) => out.push_str("<native fn>"),
+            Value::Function(ref name, _, _, _) => function(out, name.as_deref(), inspected),
+            Value::Closure(ref closure) => function(out, closure.proto.name.as_deref(), inspected),
+            Value::BoundMethod(ref bound) => {
+                function(out, bound.method.proto.name.as_deref(), inspected)
+            }
+            Value::Class(ref class) if inspected => {
+                out.push_str(&format!("<class: {}>", class.name))
+            }
+            Value::Class(ref class) => out.push_str(&format!("<class {}>", class.name)),
+            Value::Array(ref rc) => {
+                if parents.contains(&address(rc)) {
+                    out.push_str("[Circular]");
+                    return;
+                }
+                let array = rc.borrow();
+                let depth = match style {
+                    Style::Inspected(0) => {
+                        out.push_str(&format!("<array: {}>", array.len()));
+                        return;
+                    }
+                    Style::Inspected(depth) => Style::Inspected(depth - 1),
+                    Style::Printed if parents.len() == MAX_DEPTH => {
+                        out.push_str("[...]");
+                        return;
+                    }
+                    Style::Printed => Style::Printed,
+                };
+
+                if inspected {
+                    out.push_str("<array: ");
+                }
+                out.push('[');
+                parents.insert(address(rc));
+                for (i, value) in array.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    value.write(out, depth, true, parents);
+                }
+                parents.remove(&address(rc));
+                out.push(']');
+                if inspected {
+                    out.push('>');
+                }
+            }
+            Value::Object(ref rc) => {
+                if parents.contains(&address(rc)) {
+                    out.push_str("[Circular]");
+                    return;
+                }
+                let object = rc.borrow();
+                let class = object.class();
+                let name = match class {
+                    Some(ref class) => format!("{} ", class.name),
+                    None => String::new(),
+                };
+                let depth = match style {
+                    Style::Inspected(0) => {
+                        out.push_str(&format!("<{}object>", name));
+                        return;
+                    }
+                    Style::Inspected(depth) => Style::Inspected(depth - 1),
+                    Style::Printed if parents.len() == MAX_DEPTH => {
+                        out.push_str(&format!("{}{{...}}", name));
+                        return;
+                    }
+                    Style::Printed => Style::Printed,
+                };
+
+                if inspected {
+                    out.push_str(&format!("<{}object: ", name));
+                } else {
+                    out.push_str(&name);
+                }
+                out.push('{');
+                parents.insert(address(rc));
+                for (i, (key, value)) in object.fields().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    out.push_str(key);
+                    out.push_str(": ");
+                    value.write(out, depth, true, parents);
+                }
+                parents.remove(&address(rc));
+                out.push('}');
+                if inspected {
+                    out.push('>');
+                }
+            }
         }
     }
 }
 
+fn function(out: &mut String, name: Option<&str>, inspected: bool) {
+    match (name, inspected) {
+        (Some(name), true) => out.push_str(&format!("<function: {}>", name)),
+        (None, true) => out.push_str("<function>"),
+        (Some(name), false) => out.push_str(&format!("<fn {}>", name)),
+        (None, false) => out.push_str("<fn>"),
+    }
+}
+
+// Dropping the last reference to an array or object drops what it holds,
+// and so on down, which for a value nested deeply enough would overflow the
+// stack. Instead the contents are moved out and dropped one level at a time.
 impl Drop for Value {
     fn drop(&mut self) {
-        // println!("dropping {}", self);
+        let mut pending = Vec::new();
+        take_contents(self, &mut pending);
+        while let Some(mut value) = pending.pop() {
+            take_contents(&mut value, &mut pending);
+        }
+    }
+}
+
+// Moves the contents of an array or object that's about to be freed into
+// `pending`.
+fn take_contents(value: &mut Value, pending: &mut Vec<Value>) {
+    match value {
+        Value::Array(ref rc) if Rc::strong_count(rc) == 1 => {
+            if let Ok(mut array) = rc.try_borrow_mut() {
+                pending.append(&mut array);
+            }
+        }
+        Value::Object(ref rc) if Rc::strong_count(rc) == 1 => {
+            if let Ok(mut object) = rc.try_borrow_mut() {
+                object.take_values(pending);
+            }
+        }
+        _ => {}
     }
 }
@@ -0,0 +1,635 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::chunk::{Constant, FunctionProto, OpCode};
+use crate::execute::{
+    self, bind_super, err, ErrorKind, Interpreter, StackFrame, VMError, VMResult,
+};
+use crate::object::{Class, Object};
+use crate::value::Value;
+
+// A variable captured by a closure. It points into the stack while the
+// variable's scope is live, and holds the value itself once it's closed.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct Closure {
+    pub proto: Rc<FunctionProto>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.proto.name {
+            Some(ref name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<fn>"),
+        }
+    }
+}
+
+// A method looked up on an instance, which runs with the receiver in slot 0.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>,
+}
+
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    // Stack index of slot 0.
+    base: usize,
+    // Set for init called by instantiating a class, which results in the
+    // instance whatever init returns.
+    returns_receiver: bool,
+}
+
+struct Handler {
+    // The frame count and stack height when the handler was pushed.
+    frames: usize,
+    stack: usize,
+    target: usize,
+}
+
+#[derive(Default)]
+pub struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+impl Interpreter {
+    pub fn run_script(&mut self, script: Rc<FunctionProto>) -> VMResult {
+        self.on_stack(|interpreter| {
+            interpreter.limits.start();
+            let closure = interpreter.heap.closure(Closure {
+                proto: script,
+                upvalues: Vec::new(),
+            })?;
+            interpreter.call_closure(closure, None, Vec::new())
+        })
+    }
+
+    // Runs a compiled function until it returns. Natives calling script
+    // functions re-enter the VM through here.
+    pub fn call_closure(
+        &mut self,
+        closure: Rc<Closure>,
+        receiver: Option<Value>,
+        args: Vec<Value>,
+    ) -> VMResult {
+        self.limits.check_stack()?;
+        let depth = self.vm.frames.len();
+        let start = self.vm.stack.len();
+        let callee = receiver.unwrap_or_else(|| Value::Closure(closure.clone()));
+        self.vm.stack.push(callee);
+        let count = args.len();
+        self.vm.stack.extend(args);
+        if let Err(e) = self.push_frame(closure, count, false) {
+            self.vm.stack.truncate(start);
+            return Err(e);
+        }
+        self.run_frames(depth)
+    }
+
+    fn push_frame(
+        &mut self,
+        closure: Rc<Closure>,
+        count: usize,
+        returns_receiver: bool,
+    ) -> Result<(), VMError> {
+        // The script's own frame doesn't count as a call.
+        if self.vm.frames.len() > self.limits.max_call_depth {
+            return Err(execute::call_stack_exceeded());
+        }
+
+        let base = self.vm.stack.len() - count - 1;
+        // Parameters without an argument are nil, extra arguments are dropped.
+        self.vm
+            .stack
+            .resize(base + 1 + closure.proto.arity, Value::Nothing);
+        self.vm.frames.push(Frame {
+            closure,
+            ip: 0,
+            base,
+            returns_receiver,
+        });
+        Ok(())
+    }
+
+    // The frames being run, innermost first, with the instruction each is at.
+    fn vm_trace(&self) -> Vec<StackFrame> {
+        self.vm
+            .frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, frame)| {
+                let proto = &frame.closure.proto;
+                let name = match proto.name {
+                    Some(ref name) => name.as_str(),
+                    None if i == 0 => "<script>",
+                    None => "<anonymous>",
+                };
+                StackFrame {
+                    function: name.to_string(),
+                    position: proto.chunk.position_at(frame.ip.saturating_sub(1)),
+                }
+            })
+            .collect()
+    }
+
+    // Executes until the frame at `depth` returns, dispatching exceptions to
+    // the handlers of the frames above it.
+    fn run_frames(&mut self, depth: usize) -> VMResult {
+        let mut pending = None;
+        loop {
+            let e = match pending.take() {
+                Some(e) => e,
+                None => match self.execute(depth) {
+                    Ok(value) => return Ok(value),
+                    Err(e) => e,
+                },
+            };
+            // Traced before unwinding, while the frames are still there.
+            let e = e.traced(|| self.vm_trace());
+
+            match self.vm.handlers.last() {
+                Some(handler) if e.is_catchable() && handler.frames > depth => {
+                    let handler = self.vm.handlers.pop().unwrap();
+                    self.vm.frames.truncate(handler.frames);
+                    self.close_upvalues(handler.stack);
+                    self.vm.stack.truncate(handler.stack);
+
+                    // Unwinding first lets what the failed code allocated be
+                    // collected, in case the error is running out of memory.
+                    // If there still isn't room for the exception, that's
+                    // the error the next handler gets.
+                    match e.into_exception(&self.heap) {
+                        Ok(exception) => {
+                            self.vm.stack.push(exception);
+                            self.frame().ip = handler.target;
+                        }
+                        Err(e) => pending = Some(e),
+                    }
+                }
+                _ => {
+                    let base = self.vm.frames[depth].base;
+                    self.vm.handlers.retain(|handler| handler.frames <= depth);
+                    self.vm.frames.truncate(depth);
+                    self.close_upvalues(base);
+                    self.vm.stack.truncate(base);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.vm.frames.last_mut().unwrap()
+    }
+
+    // Pops the current frame, along with any handlers it left pushed.
+    fn pop_frame(&mut self) -> Frame {
+        let frames = self.vm.frames.len();
+        while matches!(self.vm.handlers.last(), Some(handler) if handler.frames >= frames) {
+            self.vm.handlers.pop();
+        }
+        self.vm.frames.pop().unwrap()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame();
+        let byte = frame.closure.proto.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_u16(&mut self) -> usize {
+        let frame = self.frame();
+        let value = frame.closure.proto.chunk.read_u16(frame.ip);
+        frame.ip += 2;
+        value as usize
+    }
+
+    // The function whose constants the current instruction refers to.
+    fn current_closure(&mut self) -> Rc<Closure> {
+        self.frame().closure.clone()
+    }
+
+    fn read_constant(&mut self) -> VMResult {
+        let index = self.read_u16();
+        let closure = self.current_closure();
+        match closure.proto.chunk.constants[index] {
+            Constant::Number(n) => Ok(Value::Number(n)),
+            Constant::String(ref string) => Ok(Value::String(string.clone())),
+            Constant::Function(_) => err(ErrorKind::Type, "function constant used as value"),
+        }
+    }
+
+    // Reads a name constant and passes it to `f`.
+    fn with_name<T>(
+        &mut self,
+        f: impl FnOnce(&mut Interpreter, &str) -> Result<T, VMError>,
+    ) -> Result<T, VMError> {
+        let index = self.read_u16();
+        let closure = self.current_closure();
+        match closure.proto.chunk.constants[index] {
+            Constant::String(ref name) => f(self, name),
+            _ => Err(VMError::Message(
+                ErrorKind::Type,
+                "expected name constant".to_string(),
+            )),
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.vm.stack.pop().unwrap()
+    }
+
+    fn peek(&self) -> Value {
+        self.vm.stack.last().unwrap().clone()
+    }
+
+    fn capture_upvalue(&mut self, index: usize) -> Result<Rc<RefCell<Upvalue>>, VMError> {
+        for upvalue in self.vm.open_upvalues.iter() {
+            if let Upvalue::Open(i) = *upvalue.borrow() {
+                if i == index {
+                    return Ok(upvalue.clone());
+                }
+            }
+        }
+
+        let upvalue = self.heap.upvalue(Upvalue::Open(index))?;
+        self.vm.open_upvalues.push(upvalue.clone());
+        Ok(upvalue)
+    }
+
+    // Moves the values of the captured variables at `from` and above off
+    // the stack.
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.vm.stack;
+        self.vm.open_upvalues.retain(|upvalue| {
+            let index = match *upvalue.borrow() {
+                Upvalue::Open(index) if index >= from => index,
+                _ => return true,
+            };
+            *upvalue.borrow_mut() = Upvalue::Closed(stack[index].clone());
+            false
+        });
+    }
+
+    fn call_instruction(&mut self, count: usize, method: bool) -> Result<(), VMError> {
+        let mut slot = self.vm.stack.len() - count - 1;
+        let base = if method {
+            slot -= 1;
+            Some(self.vm.stack.remove(slot))
+        } else {
+            None
+        };
+
+        let callee = self.vm.stack[slot].clone();
+        match callee {
+            Value::Closure(ref closure) => {
+                return self.push_frame(closure.clone(), count, false);
+            }
+            Value::BoundMethod(ref bound) => {
+                self.vm.stack[slot] = bound.receiver.clone();
+                return self.push_frame(bound.method.clone(), count, false);
+            }
+            Value::Class(ref class) => {
+                if let Some(Value::Closure(ref init)) = class.find_method("init") {
+                    let instance =
+                        Value::Object(self.heap.object(Object::instance(class.clone()))?);
+                    self.vm.stack[slot] = instance;
+                    return self.push_frame(init.clone(), count, true);
+                }
+            }
+            _ => {}
+        }
+
+        // Natives and everything else are called like the tree-walker does.
+        let args = self.vm.stack.split_off(slot + 1);
+        self.vm.stack.pop();
+        let result = self.call_value(callee, base, args)?;
+        self.vm.stack.push(result);
+        Ok(())
+    }
+
+    // Calls like call_instruction, except that a script function takes over
+    // the current frame. Everything else returns its result through the
+    // Return following the instruction.
+    fn tail_call_instruction(&mut self, count: usize, method: bool) -> Result<(), VMError> {
+        let slot = self.vm.stack.len() - count - 1;
+        let (closure, receiver) = match self.vm.stack[slot] {
+            Value::Closure(ref closure) => (closure.clone(), None),
+            Value::BoundMethod(ref bound) => (bound.method.clone(), Some(bound.receiver.clone())),
+            _ => return self.call_instruction(count, method),
+        };
+        // The frame of an init call holds the instance it results in.
+        if self.frame().returns_receiver {
+            return self.call_instruction(count, method);
+        }
+
+        if let Some(receiver) = receiver {
+            self.vm.stack[slot] = receiver;
+        }
+        let frame = self.pop_frame();
+        self.close_upvalues(frame.base);
+        // The callee and arguments move down to where the frame started,
+        // over its locals and, for method calls, the base.
+        self.vm.stack.drain(frame.base..slot);
+        self.push_frame(closure, count, false)
+    }
+
+    fn binary_instruction(&mut self, op: fn(Value, Value) -> VMResult) -> Result<(), VMError> {
+        let right = self.pop();
+        let left = self.pop();
+        let result = op(left, right)?;
+        self.vm.stack.push(result);
+        Ok(())
+    }
+
+    fn execute(&mut self, depth: usize) -> VMResult {
+        loop {
+            self.limits.step()?;
+            let byte = self.read_byte();
+            let op = match OpCode::from_byte(byte) {
+                Some(op) => op,
+                None => return err(ErrorKind::Type, "invalid instruction"),
+            };
+
+            match op {
+                OpCode::Constant => {
+                    let value = self.read_constant()?;
+                    self.vm.stack.push(value);
+                }
+                OpCode::Nil => self.vm.stack.push(Value::Nothing),
+                OpCode::True => self.vm.stack.push(Value::Boolean(true)),
+                OpCode::False => self.vm.stack.push(Value::Boolean(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let index = self.frame().base + slot;
+                    let value = self.vm.stack[index].clone();
+                    self.vm.stack.push(value);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let index = self.frame().base + slot;
+                    self.vm.stack[index] = self.peek();
+                }
+                OpCode::GetGlobal => {
+                    let value = self.with_name(|vm, name| vm.get_global(name))?;
+                    self.vm.stack.push(value);
+                }
+                OpCode::SetGlobal => {
+                    let value = self.peek();
+                    self.with_name(|vm, name| vm.set_global(name, value))?;
+                }
+                OpCode::DefineGlobal => {
+                    let value = self.pop();
+                    self.with_name(|vm, name| {
+                        vm.define_global(name, value);
+                        Ok(())
+                    })?;
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    let value = match *upvalue.borrow() {
+                        Upvalue::Open(index) => self.vm.stack[index].clone(),
+                        Upvalue::Closed(ref value) => value.clone(),
+                    };
+                    self.vm.stack.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    let value = self.peek();
+                    let mut upvalue = upvalue.borrow_mut();
+                    match *upvalue {
+                        Upvalue::Open(index) => self.vm.stack[index] = value,
+                        Upvalue::Closed(ref mut closed) => *closed = value,
+                    }
+                }
+                OpCode::Get => {
+                    let key = self.pop();
+                    let base = self.pop();
+                    let value = self.get(base, key)?;
+                    self.vm.stack.push(value);
+                }
+                OpCode::GetMethod => {
+                    let key = self.pop();
+                    let base = self.peek();
+                    let value = self.get(base, key)?;
+                    self.vm.stack.push(value);
+                }
+                OpCode::Set => {
+                    let value = self.pop();
+                    let key = self.pop();
+                    let base = self.pop();
+                    let value = self.set(base, key, value)?;
+                    self.vm.stack.push(value);
+                }
+                OpCode::Slice => {
+                    let end = self.pop();
+                    let start = self.pop();
+                    let base = self.pop();
+                    let value = self.slice(base, start, end)?;
+                    self.vm.stack.push(value);
+                }
+                OpCode::SetSlice => {
+                    let value = self.pop();
+                    let end = self.pop();
+                    let start = self.pop();
+                    let base = self.pop();
+                    let value = self.set_slice(base, start, end, value)?;
+                    self.vm.stack.push(value);
+                }
+                OpCode::GetSuper => {
+                    let superclass = self.pop();
+                    let this = self.pop();
+                    let method = self.with_name(|interpreter, name| {
+                        bind_super(&interpreter.heap, superclass, this, name)
+                    })?;
+                    self.vm.stack.push(method);
+                }
+                OpCode::Equal => self.binary_instruction(execute::equal)?,
+                OpCode::NotEqual => self.binary_instruction(execute::not_equal)?,
+                OpCode::Greater => self.binary_instruction(execute::greater)?,
+                OpCode::GreaterEqual => self.binary_instruction(execute::greater_equal)?,
+                OpCode::Less => self.binary_instruction(execute::less)?,
+                OpCode::LessEqual => self.binary_instruction(execute::less_equal)?,
+                OpCode::Add => self.binary_instruction(execute::add)?,
+                OpCode::Subtract => self.binary_instruction(execute::subtract)?,
+                OpCode::Multiply => self.binary_instruction(execute::multiply)?,
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.vm.stack.push(Value::Boolean(!value.is_truthy()));
+                }
+                OpCode::Print => {
+                    let value = self.pop();
+                    self.print(&value)?;
+                }
+                OpCode::Jump => {
+                    let offset = self.read_u16();
+                    self.frame().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_u16();
+                    if !self.pop().is_truthy() {
+                        self.frame().ip += offset;
+                    }
+                }
+                OpCode::JumpIfFalseOrPop => {
+                    let offset = self.read_u16();
+                    if self.vm.stack.last().unwrap().is_truthy() {
+                        self.pop();
+                    } else {
+                        self.frame().ip += offset;
+                    }
+                }
+                OpCode::JumpIfTrueOrPop => {
+                    let offset = self.read_u16();
+                    if self.vm.stack.last().unwrap().is_truthy() {
+                        self.frame().ip += offset;
+                    } else {
+                        self.pop();
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_u16();
+                    self.frame().ip -= offset;
+                }
+                OpCode::Call => {
+                    let count = self.read_byte() as usize;
+                    self.call_instruction(count, false)?;
+                }
+                OpCode::CallMethod => {
+                    let count = self.read_byte() as usize;
+                    self.call_instruction(count, true)?;
+                }
+                OpCode::TailCall => {
+                    let count = self.read_byte() as usize;
+                    self.tail_call_instruction(count, false)?;
+                }
+                OpCode::TailCallMethod => {
+                    let count = self.read_byte() as usize;
+                    self.tail_call_instruction(count, true)?;
+                }
+                OpCode::Closure => {
+                    let index = self.read_u16();
+                    let closure = self.current_closure();
+                    let proto = match closure.proto.chunk.constants[index] {
+                        Constant::Function(ref proto) => proto.clone(),
+                        _ => return err(ErrorKind::Type, "expected function constant"),
+                    };
+
+                    let base = self.frame().base;
+                    let mut upvalues = Vec::with_capacity(proto.upvalue_count);
+                    for _ in 0..proto.upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        let upvalue = if is_local {
+                            self.capture_upvalue(base + index)?
+                        } else {
+                            closure.upvalues[index].clone()
+                        };
+                        upvalues.push(upvalue);
+                    }
+
+                    let value = Value::Closure(self.heap.closure(Closure { proto, upvalues })?);
+                    self.vm.stack.push(value);
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.vm.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Return => {
+                    let mut result = self.pop();
+                    let frame = self.pop_frame();
+                    if frame.returns_receiver {
+                        result = self.vm.stack[frame.base].clone();
+                    }
+                    self.close_upvalues(frame.base);
+                    self.vm.stack.truncate(frame.base);
+
+                    if self.vm.frames.len() == depth {
+                        return Ok(result);
+                    }
+                    self.vm.stack.push(result);
+                }
+                OpCode::Array => {
+                    let count = self.read_u16();
+                    let start = self.vm.stack.len() - count;
+                    let values = self.vm.stack.split_off(start);
+                    let array = self.heap.array(values)?;
+                    self.vm.stack.push(Value::Array(array));
+                }
+                OpCode::Object => {
+                    let count = self.read_u16();
+                    let start = self.vm.stack.len() - 2 * count;
+                    let mut fields = self.vm.stack.split_off(start).into_iter();
+
+                    let mut object = Object::new();
+                    while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+                        if let Value::String(ref name) = name {
+                            object.set(name.clone(), value);
+                        }
+                    }
+                    let object = self.heap.object(object)?;
+                    self.vm.stack.push(Value::Object(object));
+                }
+                OpCode::Class => {
+                    let name = self.with_name(|_, name| Ok(name.to_string()))?;
+                    let count = self.read_byte() as usize;
+                    let has_superclass = self.read_byte() == 1;
+
+                    let start = self.vm.stack.len() - 2 * count;
+                    let mut methods = self.vm.stack.split_off(start).into_iter();
+                    let superclass = if has_superclass {
+                        match self.pop() {
+                            Value::Class(ref class) => Some(class.clone()),
+                            _ => return err(ErrorKind::Type, "superclass must be a class"),
+                        }
+                    } else {
+                        None
+                    };
+
+                    let mut class = Class::new(name, superclass);
+                    while let (Some(name), Some(method)) = (methods.next(), methods.next()) {
+                        if let Value::String(ref name) = name {
+                            class.define_method(name.clone(), method);
+                        }
+                    }
+                    let class = self.heap.class(class)?;
+                    self.vm.stack.push(Value::Class(class));
+                }
+                OpCode::Throw => {
+                    let value = self.pop();
+                    return Err(VMError::Throw(value));
+                }
+                OpCode::PushHandler => {
+                    let offset = self.read_u16();
+                    let handler = Handler {
+                        frames: self.vm.frames.len(),
+                        stack: self.vm.stack.len(),
+                        target: self.frame().ip + offset,
+                    };
+                    self.vm.handlers.push(handler);
+                }
+                OpCode::PopHandler => {
+                    self.vm.handlers.pop();
+                }
+            }
+        }
+    }
+}